@@ -41,6 +41,24 @@ impl NHRunnable for interface::CleanMode {
         // What profiles to clean depending on the call mode
         let uid = nix::unistd::Uid::effective();
         let args = match self {
+            interface::CleanMode::Home(args) => {
+                let user = nix::unistd::User::from_uid(uid)?.unwrap();
+                let home = PathBuf::from(std::env::var("HOME")?);
+                let profile = [
+                    PathBuf::from("/nix/var/nix/profiles/per-user")
+                        .join(user.name)
+                        .join("home-manager"),
+                    home.join(".local/state/nix/profiles/home-manager"),
+                ]
+                .into_iter()
+                .find(|p| p.symlink_metadata().is_ok())
+                .context("Couldn't find a home-manager profile")?;
+
+                return wipe_history(&profile, args);
+            }
+            interface::CleanMode::Wipe(args) => {
+                return wipe_history(&args.profile, &args.common);
+            }
             interface::CleanMode::Profile(args) => {
                 profiles.push(args.profile.clone());
                 is_profile_clean = true;
@@ -72,10 +90,10 @@ impl NHRunnable for interface::CleanMode {
                 }
                 let user = nix::unistd::User::from_uid(uid)?.unwrap();
                 profiles.extend(profiles_in_dir(
-                    &PathBuf::from(std::env::var("HOME")?).join(".local/state/nix/profiles"),
+                    PathBuf::from(std::env::var("HOME")?).join(".local/state/nix/profiles"),
                 ));
                 profiles.extend(profiles_in_dir(
-                    &PathBuf::from("/nix/var/nix/profiles/per-user").join(user.name),
+                    PathBuf::from("/nix/var/nix/profiles/per-user").join(user.name),
                 ));
                 args
             }
@@ -156,45 +174,48 @@ impl NHRunnable for interface::CleanMode {
         }
 
         // Present the user the information about the paths to clean
-        use owo_colors::OwoColorize;
-        println!();
-        println!("{}", "Welcome to nh clean".bold());
-        println!("Keeping {} generation(s)", args.keep.green());
-        println!("Keeping paths newer than {}", args.keep_since.green());
-        println!();
-        println!("legend:");
-        println!("{}: path to be kept", "OK".green());
-        println!("{}: path to be removed", "DEL".red());
-        println!();
-        if !gcroots_tagged.is_empty() {
-            println!(
-                "{}",
-                "gcroots (matching the following regex patterns)"
-                    .blue()
-                    .bold()
-            );
-            for re in regexes {
-                println!("- {}  {}", "RE".purple(), re);
-            }
-            for (path, tbr) in &gcroots_tagged {
-                if *tbr {
-                    println!("- {} {}", "DEL".red(), path.to_string_lossy());
-                } else {
-                    println!("- {} {}", "OK ".green(), path.to_string_lossy());
+        // The plan is still needed to answer --ask
+        if !logging::is_quiet() || args.ask {
+            use owo_colors::OwoColorize;
+            println!();
+            println!("{}", "Welcome to nh clean".bold());
+            println!("Keeping {} generation(s)", args.keep.green());
+            println!("Keeping paths newer than {}", args.keep_since.green());
+            println!();
+            println!("legend:");
+            println!("{}: path to be kept", "OK".green());
+            println!("{}: path to be removed", "DEL".red());
+            println!();
+            if !gcroots_tagged.is_empty() {
+                println!(
+                    "{}",
+                    "gcroots (matching the following regex patterns)"
+                        .blue()
+                        .bold()
+                );
+                for re in regexes {
+                    println!("- {}  {}", "RE".purple(), re);
                 }
+                for (path, tbr) in &gcroots_tagged {
+                    if *tbr {
+                        println!("- {} {}", "DEL".red(), path.to_string_lossy());
+                    } else {
+                        println!("- {} {}", "OK ".green(), path.to_string_lossy());
+                    }
+                }
+                println!();
             }
-            println!();
-        }
-        for (profile, generations_tagged) in profiles_tagged.iter() {
-            println!("{}", profile.to_string_lossy().blue().bold());
-            for (gen, tbr) in generations_tagged.iter().rev() {
-                if *tbr {
-                    println!("- {} {}", "DEL".red(), gen.path.to_string_lossy());
-                } else {
-                    println!("- {} {}", "OK ".green(), gen.path.to_string_lossy());
-                };
+            for (profile, generations_tagged) in profiles_tagged.iter() {
+                println!("{}", profile.to_string_lossy().blue().bold());
+                for (gen, tbr) in generations_tagged.iter().rev() {
+                    if *tbr {
+                        println!("- {} {}", "DEL".red(), gen.path.to_string_lossy());
+                    } else {
+                        println!("- {} {}", "OK ".green(), gen.path.to_string_lossy());
+                    };
+                }
+                println!();
             }
-            println!();
         }
 
         // Clean the paths
@@ -222,7 +243,7 @@ impl NHRunnable for interface::CleanMode {
         }
 
         commands::CommandBuilder::default()
-            .args(["nix", "store", "gc"])
+            .args([util::nix_bin(), "store", "gc"])
             .dry(args.dry)
             .message("Performing garbage collection on the nix store")
             .build()?
@@ -236,6 +257,7 @@ impl NHRunnable for interface::CleanMode {
 fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     let mut res = Vec::new();
     let dir = dir.as_ref();
+    let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
 
     match dir.read_dir() {
         Ok(read_dir) => {
@@ -250,9 +272,7 @@ fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
                                 .expect("Failed to get filename")
                                 .to_string_lossy();
 
-                            let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
-
-                            if let Some(_) = generation_regex.captures(&name) {
+                            if generation_regex.is_match(&name) {
                                 res.push(path);
                             }
                         }
@@ -337,6 +357,103 @@ fn cleanable_generations(
     Ok(result)
 }
 
+fn wipe_history(profile: &Path, args: &interface::CleanWipeArgs) -> Result<()> {
+    if profile.symlink_metadata().is_err() {
+        bail!("Profile {:?} doesn't exist", profile);
+    }
+
+    if args.ask {
+        info!("Wipe the history of {}?", profile.to_string_lossy());
+        if !dialoguer::Confirm::new().default(false).interact()? {
+            return Ok(());
+        }
+    }
+
+    wipe_history_command(profile, args.older_than, args.dry)?.exec()
+}
+
+fn wipe_history_command(
+    profile: &Path,
+    older_than: Option<humantime::Duration>,
+    dry: bool,
+) -> Result<commands::Command> {
+    let mut cmd = commands::CommandBuilder::default();
+    cmd.args([util::nix_bin(), "profile", "wipe-history", "--profile"])
+        .args([profile])
+        .dry(dry)
+        .message(format!(
+            "Wiping the history of {}",
+            profile.to_string_lossy()
+        ));
+
+    if let Some(older_than) = older_than {
+        // nix only understands days. Rounding up keeps a bit more, where rounding down would
+        // turn anything under a day into 0d and wipe every old generation
+        let days = older_than.as_secs().div_ceil(24 * 60 * 60);
+        cmd.args(["--older-than".to_string(), format!("{days}d")]);
+    }
+
+    Ok(cmd.build()?)
+}
+
+#[test]
+fn test_wipe_history_command() {
+    let profile = Path::new("/home/user/.local/state/nix/profiles/home-manager");
+
+    assert_eq!(
+        wipe_history_command(profile, Some("30d 12h".parse().unwrap()), false)
+            .unwrap()
+            .to_args(),
+        [
+            "nix",
+            "profile",
+            "wipe-history",
+            "--profile",
+            "/home/user/.local/state/nix/profiles/home-manager",
+            "--older-than",
+            "31d",
+        ]
+    );
+    assert_eq!(
+        wipe_history_command(profile, Some("12h".parse().unwrap()), false)
+            .unwrap()
+            .to_args()
+            .last()
+            .unwrap(),
+        "1d"
+    );
+    assert_eq!(
+        wipe_history_command(profile, Some("7d".parse().unwrap()), false)
+            .unwrap()
+            .to_args()
+            .last()
+            .unwrap(),
+        "7d"
+    );
+    assert_eq!(
+        wipe_history_command(profile, None, false)
+            .unwrap()
+            .to_args(),
+        [
+            "nix",
+            "profile",
+            "wipe-history",
+            "--profile",
+            "/home/user/.local/state/nix/profiles/home-manager",
+        ]
+    );
+}
+
+#[test]
+fn test_wipe_history_missing_profile() {
+    let args = interface::CleanWipeArgs {
+        older_than: None,
+        dry: true,
+        ask: false,
+    };
+    assert!(wipe_history(Path::new("/nonexistent/profile"), &args).is_err());
+}
+
 fn remove_path_nofail(path: &Path) {
     info!("Removing {}", path.to_string_lossy());
     if let Err(err) = std::fs::remove_file(path) {
@@ -1,18 +1,64 @@
 use color_eyre::{
-    eyre::{bail, Context},
+    eyre::{bail, eyre, Context, ContextCompat},
     Result,
 };
 
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 use subprocess::{Exec, ExitStatus, Redirection};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::interface::FlakeRef;
+use crate::interface::{FlakeKind, FlakeRef};
+use crate::internal_json::{self, BuildLog};
+use crate::plan::BuildPlan;
+use crate::progress::ProgressTracker;
+use crate::{flake, prefix, signals, util};
+
+static SHOW_COMMAND: AtomicBool = AtomicBool::new(false);
+
+/// Print every command line before running it
+pub fn set_show_command(show: bool) {
+    SHOW_COMMAND.store(show, Ordering::Relaxed);
+}
+
+fn show_command(stages: &[&[OsString]]) {
+    if SHOW_COMMAND.load(Ordering::Relaxed) {
+        let stages: Vec<_> = stages.iter().map(|args| shell_join(args)).collect();
+        eprintln!("$ {}", stages.join(" | "));
+    }
+}
+
+/// Renders the arguments as a command line that can be pasted into a shell
+pub fn shell_join<S: AsRef<OsStr>>(args: &[S]) -> String {
+    let args: Vec<_> = args.iter().map(|arg| shell_quote(arg.as_ref())).collect();
+    args.join(" ")
+}
+
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%^#".contains(c);
+
+    // A # only starts a comment at the beginning of a word
+    if !arg.is_empty() && !arg.starts_with('#') && arg.chars().all(is_safe) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r#"'\''"#))
+    }
+}
 
 #[derive(Debug, derive_builder::Builder)]
 #[builder(derive(Debug), setter(into))]
+#[builder_struct_attr(doc = r"Builder of a [`Command`]
+
+[`CommandBuilder::args`] appends to the arguments given so far instead of replacing them, so
+reusing a builder keeps stale arguments. To share a base configuration, clone the builder for
+every command.")]
 pub struct Command {
     /// Whether to actually run the command or just log it
     #[builder(default = "false")]
@@ -23,9 +69,29 @@ pub struct Command {
     /// Arguments 0..N
     #[builder(setter(custom))]
     args: Vec<OsString>,
+    /// Working directory to run the command in
+    #[builder(setter(strip_option), default = "None")]
+    cwd: Option<PathBuf>,
+    /// Extra environment variables for the command
+    #[builder(setter(custom), default)]
+    env: Vec<(OsString, OsString)>,
+    /// Capture stderr along with stdout, for tools that report on stderr
+    #[builder(default)]
+    merge_stderr: bool,
+    /// Put this in front of every line the command outputs
+    #[builder(setter(strip_option), default = "crate::prefix::output_prefix()")]
+    output_prefix: Option<String>,
 }
 
 impl CommandBuilder {
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.env
+            .get_or_insert_with(Default::default)
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    /// Appends arguments after the ones given so far
     pub fn args<S, I>(&mut self, input: I) -> &mut Self
     where
         S: AsRef<OsStr>,
@@ -39,13 +105,39 @@ impl CommandBuilder {
 }
 
 impl Command {
+    /// Arguments 0..N that would be executed
+    pub fn to_args(&self) -> Vec<OsString> {
+        self.args.clone()
+    }
+
+    /// Value of an extra environment variable set for the command
+    #[cfg(test)]
+    pub fn env_var(&self, key: &str) -> Option<&OsStr> {
+        self.env
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_os_str())
+    }
+
+    fn to_exec(&self, head: &OsString, tail: &[OsString]) -> Exec {
+        let mut cmd = Exec::cmd(head).args(tail);
+        if let Some(cwd) = &self.cwd {
+            cmd = cmd.cwd(cwd);
+        }
+        for (key, value) in &self.env {
+            cmd = cmd.env(key, value);
+        }
+        cmd
+    }
+
     pub fn exec(&self) -> Result<()> {
         let [head, tail @ ..] = &*self.args else {
             bail!("Args was length 0");
         };
 
-        let cmd = Exec::cmd(head)
-            .args(tail)
+        let cmd = self
+            .to_exec(head, tail)
             .stderr(Redirection::None)
             .stdout(Redirection::None);
 
@@ -53,12 +145,21 @@ impl Command {
             info!("{}", m);
         }
         debug!(?cmd);
+        show_command(&[&self.args]);
 
         if !self.dry {
+            let result = match &self.output_prefix {
+                Some(prefix) => exec_prefixed(cmd, prefix),
+                None => cmd.popen().map_err(Into::into).and_then(|mut child| {
+                    let _tracked = signals::track(child.pid());
+                    Ok(child.wait()?)
+                }),
+            }
+            .and_then(check_exit);
             if let Some(m) = &self.message {
-                cmd.join().wrap_err(m.clone())?;
+                result.wrap_err(m.clone())?;
             } else {
-                cmd.join()?;
+                result?;
             }
         }
 
@@ -70,39 +171,257 @@ impl Command {
             bail!("Args was length 0");
         };
 
-        let cmd = Exec::cmd(head)
-            .args(tail)
-            .stderr(Redirection::None)
+        let stderr = if self.merge_stderr {
+            Redirection::Merge
+        } else {
+            Redirection::None
+        };
+        let cmd = self
+            .to_exec(head, tail)
+            .stderr(stderr)
             .stdout(Redirection::Pipe);
 
         if let Some(m) = &self.message {
             info!("{}", m);
         }
         debug!(?cmd);
+        show_command(&[&self.args]);
 
         if !self.dry {
-            Ok(Some(cmd.capture()?.stdout_str()))
+            let mut child = cmd.popen()?;
+            let _tracked = signals::track(child.pid());
+            let (stdout, _) = child.communicate_bytes(None)?;
+            check_exit(child.wait()?)?;
+            Ok(Some(
+                String::from_utf8_lossy(&stdout.unwrap_or_default()).into_owned(),
+            ))
         } else {
             Ok(None)
         }
     }
+
+    /// Like [`Command::exec_capture`], but hands every line of stdout to `on_line` as it
+    /// arrives instead of keeping the whole output, returning the number of lines
+    pub fn exec_stream<F>(&self, mut on_line: F) -> Result<Option<usize>>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let [head, tail @ ..] = &*self.args else {
+            bail!("Args was length 0");
+        };
+
+        let cmd = self
+            .to_exec(head, tail)
+            .stderr(Redirection::None)
+            .stdout(Redirection::Pipe);
+
+        if let Some(m) = &self.message {
+            info!("{}", m);
+        }
+        debug!(?cmd);
+        show_command(&[&self.args]);
+
+        if self.dry {
+            return Ok(None);
+        }
+
+        let mut child = cmd.popen()?;
+        let _tracked = signals::track(child.pid());
+        let mut reader = BufReader::new(child.stdout.take().wrap_err("Taking stdout")?);
+
+        let mut lines = 0;
+        let mut line = Vec::new();
+        let result = loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {
+                    lines += 1;
+                    let text = String::from_utf8_lossy(&line);
+                    if let Err(err) = on_line(text.trim_end_matches('\n')) {
+                        break Err(err);
+                    }
+                }
+                Err(err) => break Err(err.into()),
+            }
+        };
+
+        if result.is_err() {
+            let _ = child.kill();
+        }
+        drop(reader);
+        let exit = child.wait()?;
+        result?;
+        check_exit(exit)?;
+
+        Ok(Some(lines))
+    }
 }
 
-#[derive(Debug, derive_builder::Builder)]
-#[builder(setter(into))]
+/// Runs `cmd` with every line of its stdout and stderr prefixed with `prefix`
+fn exec_prefixed(cmd: Exec, prefix: &str) -> Result<ExitStatus> {
+    let mut child = cmd
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .popen()?;
+    let _tracked = signals::track(child.pid());
+
+    let stdout = child.stdout.take().wrap_err("Taking stdout")?;
+    let stderr = child.stderr.take().wrap_err("Taking stderr")?;
+    let stderr_prefix = prefix.to_string();
+    let stderr_relay = std::thread::spawn(move || {
+        prefix::copy_prefixed(stderr, std::io::stderr(), &stderr_prefix)
+    });
+
+    prefix::copy_prefixed(stdout, std::io::stdout(), prefix)?;
+    stderr_relay
+        .join()
+        .map_err(|_| eyre!("Relaying stderr panicked"))??;
+
+    Ok(child.wait()?)
+}
+
+fn check_exit(exit: ExitStatus) -> Result<()> {
+    match exit {
+        ExitStatus::Exited(0) => Ok(()),
+        other => bail!(ExitError(other)),
+    }
+}
+
+/// Where the stderr of nix goes when building without nom
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StderrPolicy {
+    /// Into stdout, echoing everything but the out paths to the terminal
+    #[default]
+    Merge,
+    /// Straight to the terminal, without nh seeing the warnings and errors of nix, nor putting
+    /// the output prefix in front of them
+    Inherit,
+    /// Kept off the terminal, inspected, and only printed if the build fails
+    Capture,
+}
+
+impl StderrPolicy {
+    fn redirection(self) -> Redirection {
+        match self {
+            Self::Merge => Redirection::Merge,
+            Self::Inherit => Redirection::None,
+            Self::Capture => Redirection::Pipe,
+        }
+    }
+}
+
+#[derive(Debug, Clone, derive_builder::Builder)]
+#[builder(setter(into), build_fn(validate = "Self::validate"))]
 pub struct BuildCommand {
-    /// Human-readable message regarding what the command does
-    message: String,
-    // Flakeref to build
-    flakeref: String,
+    /// Human-readable message regarding what the command does, derived from the flakeref if unset
+    #[builder(setter(strip_option), default = "None")]
+    message: Option<String>,
+    // Flakerefs to build, all in the same nix invocation
+    #[builder(setter(custom))]
+    flakerefs: Vec<String>,
     // Extra arguments passed to nix build
     #[builder(setter(custom))]
     extra_args: Vec<OsString>,
     /// Use nom for the nix build
     nom: bool,
+    /// Print the full build logs, and the log of the failed derivation when using nom
+    #[builder(default = "false")]
+    print_build_logs: bool,
+    /// Let remote builders fetch from substituters instead of copying from this machine
+    #[builder(default = "false")]
+    builders_use_substitutes: bool,
+    /// URL of an alternate store to build into
+    #[builder(default)]
+    store: Option<String>,
+    /// System to build for, like aarch64-linux
+    #[builder(default)]
+    system: Option<String>,
+    /// Print the given number of slowest derivations after building
+    #[builder(default)]
+    build_report: Option<usize>,
+    /// Throw away flake.lock and create a new one. Only for flakes
+    #[builder(default = "false")]
+    recreate_lock_file: bool,
+    /// Consider every cached flake input as stale. Only for flakes
+    #[builder(default = "false")]
+    refresh: bool,
+    /// Don't write or update flake.lock, for flakes that live in a read-only location
+    #[builder(default = "false")]
+    read_only_lock_file: bool,
+    /// Silence nix, only printing its logs if the build fails. Disables nom
+    #[builder(default = "crate::logging::is_quiet()")]
+    quiet: bool,
+    /// File to write nix's internal-json events to, one JSON object per line. Needs nom
+    #[builder(default)]
+    trace_file: Option<PathBuf>,
+    /// Retry with nix-command and flakes enabled if nix reports they are disabled
+    #[builder(default = "false")]
+    auto_features: bool,
+    /// Enable nix-command and flakes for this build only
+    #[builder(default = "false")]
+    experimental_features: bool,
+    /// Retry with a single build job if a builder runs out of memory
+    #[builder(default = "false")]
+    auto_recover: bool,
+    /// Build one derivation at a time, to use less memory
+    #[builder(default = "false")]
+    single_job: bool,
+    /// Put this in front of every line of the logs of nix. Disables nom
+    #[builder(default = "crate::prefix::output_prefix()")]
+    output_prefix: Option<String>,
+    /// Repeat the warnings of nix at the end of the build
+    #[builder(default = "true")]
+    warning_summary: bool,
+    /// Only evaluate the installables to their derivations, without building anything
+    #[builder(default = "false")]
+    eval_only: bool,
+    /// Print the NAR hash of every output after building, to compare builds across machines
+    #[builder(default = "false")]
+    report_hashes: bool,
+    /// Only print what would be built and fetched, listing the paths with `plan_paths`
+    #[builder(default = "false")]
+    plan: bool,
+    #[builder(default = "false")]
+    plan_paths: bool,
+    /// Report the progress of nix as JSON lines on stderr instead of its logs. Disables nom
+    #[builder(default = "false")]
+    progress_json: bool,
+    /// Where the stderr of nix goes. Doesn't apply to nom, which only gets the events of nix
+    #[builder(default)]
+    stderr_policy: StderrPolicy,
 }
 
 impl BuildCommandBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(Some(store)) = &self.store {
+            if store.is_empty() {
+                return Err(String::from("Store URL can't be empty"));
+            }
+        }
+        if self.flakerefs.as_ref().is_none_or(Vec::is_empty) {
+            return Err(String::from("At least one flakeref must be built"));
+        }
+        Ok(())
+    }
+
+    /// Adds a flakeref to build
+    pub fn flakeref<S: Into<String>>(&mut self, flakeref: S) -> &mut Self {
+        self.flakerefs([flakeref])
+    }
+
+    /// Adds several flakerefs to build at once
+    pub fn flakerefs<S, I>(&mut self, input: I) -> &mut Self
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = S>,
+    {
+        self.flakerefs
+            .get_or_insert_with(Default::default)
+            .extend(input.into_iter().map(Into::into));
+        self
+    }
+
     pub fn extra_args<S, I>(&mut self, input: I) -> &mut Self
     where
         S: AsRef<OsStr>,
@@ -116,70 +435,1332 @@ impl BuildCommandBuilder {
 }
 
 impl BuildCommand {
-    pub fn exec(&self) -> Result<()> {
-        info!("{}", self.message);
-
-        let exit = if self.nom {
-            let cmd = {
-                Exec::cmd("nix")
-                    .args(&[
-                        "build",
-                        &self.flakeref,
-                        "--log-format",
-                        "internal-json",
-                        "--verbose",
-                    ])
-                    .args(&self.extra_args)
-                    .stdout(Redirection::Pipe)
-                    .stderr(Redirection::Merge)
-                    | Exec::cmd("nom").args(&["--json"])
+    /// Arguments of the nix invocation, without the nom stage
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![util::nix_bin().into(), "build".into()];
+        args.extend(self.flakerefs.iter().map(|flakeref| {
+            OsString::from(match &self.system {
+                Some(system) => flake::resolve_system_attr(flakeref, system),
+                None => flakeref.clone(),
+            })
+        }));
+        args.push("--print-out-paths".into());
+
+        if self.experimental_features {
+            args.extend(
+                ["--extra-experimental-features", "nix-command flakes"].map(OsString::from),
+            );
+        }
+
+        if self.use_nom() || self.progress_json {
+            args.extend(["--log-format", "internal-json", "--verbose"].map(OsString::from));
+        } else if self.quiet {
+            args.push("--quiet".into());
+        } else if self.print_build_logs {
+            args.push("-L".into());
+        }
+
+        if self.builders_use_substitutes {
+            args.push("--builders-use-substitutes".into());
+        }
+
+        if let Some(store) = &self.store {
+            args.extend(["--store".into(), store.into()]);
+        }
+
+        if let Some(system) = &self.system {
+            args.extend(["--system".into(), system.into()]);
+        }
+
+        if self.recreate_lock_file {
+            args.push("--recreate-lock-file".into());
+        }
+
+        if self.refresh {
+            args.push("--refresh".into());
+        }
+
+        if self.read_only_lock_file {
+            args.extend(["--no-write-lock-file", "--no-update-lock-file"].map(OsString::from));
+        }
+
+        args.extend(self.extra_args.iter().cloned());
+
+        // Last, so that it overrides a --max-jobs of the extra args
+        if self.single_job {
+            args.extend(["--max-jobs", "1"].map(OsString::from));
+        }
+
+        args
+    }
+
+    /// Arguments of the nix invocation evaluating `flakeref` to its derivation
+    pub fn eval_args(&self, flakeref: &str) -> Vec<OsString> {
+        let installable = match &self.system {
+            Some(system) => flake::resolve_system_attr(flakeref, system),
+            None => flakeref.to_string(),
+        };
+        let mut args: Vec<OsString> = [util::nix_bin(), "eval", "--raw"]
+            .map(OsString::from)
+            .into();
+        args.push(format!("{installable}.drvPath").into());
+
+        if self.experimental_features {
+            args.extend(
+                ["--extra-experimental-features", "nix-command flakes"].map(OsString::from),
+            );
+        }
+
+        if self.refresh {
+            args.push("--refresh".into());
+        }
+
+        if self.read_only_lock_file {
+            args.extend(["--no-write-lock-file", "--no-update-lock-file"].map(OsString::from));
+        }
+
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Problems with the options that don't prevent building
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if let Some(system) = &self.system {
+            if !util::can_build_for(system) {
+                warnings.push(format!(
+                    "Neither a remote builder nor binfmt emulation seem to be configured for {system}"
+                ));
             }
-            .stdout(Redirection::None);
-            debug!(?cmd);
-            cmd.join()
+        }
+
+        if self.build_report.is_some() && !self.use_nom() {
+            warnings.push(String::from(
+                "The build report is only available when using nom",
+            ));
+        }
+
+        if self.trace_file.is_some() && !self.use_nom() {
+            warnings.push(String::from(
+                "The trace file is only written when using nom",
+            ));
+        }
+
+        if self.recreate_lock_file {
+            warnings.push(String::from(
+                "--recreate-lock-file will overwrite flake.lock with freshly locked inputs",
+            ));
+        }
+
+        warnings
+    }
+
+    fn use_nom(&self) -> bool {
+        self.nom && !self.quiet && self.output_prefix.is_none() && !self.progress_json
+    }
+
+    fn message(&self) -> String {
+        self.message
+            .clone()
+            .unwrap_or_else(|| format!("Building {}", self.flakerefs.join(", ")))
+    }
+
+    /// Prefix in front of every line of the logs, if any
+    #[cfg(test)]
+    pub fn output_prefix(&self) -> Option<&str> {
+        self.output_prefix.as_deref()
+    }
+
+    /// Builds the installables, returning their output paths
+    ///
+    /// With `eval_only`, the derivations are printed and returned instead. With `plan`, nothing
+    /// is built or returned.
+    pub fn exec(&self) -> Result<Vec<PathBuf>> {
+        if self.eval_only {
+            return self.exec_eval();
+        }
+        if self.plan {
+            self.exec_plan()?;
+            return Ok(Vec::new());
+        }
+
+        let message = self.message();
+        info!("{}", message);
+
+        for warning in self.warnings() {
+            warn!("{warning}");
+        }
+
+        let args = self.to_args();
+        let (exit, log) = if self.use_nom() {
+            self.exec_nom(&args).wrap_err(message.clone())?
         } else {
-            let cmd = Exec::cmd("nix")
-                .args(&["build", &self.flakeref])
-                .args(&self.extra_args)
-                .stdout(Redirection::None)
-                .stderr(Redirection::Merge);
-
-            debug!(?cmd);
-            cmd.join()
+            self.exec_plain(&args).wrap_err(message)?
         };
 
-        match exit.wrap_err(self.message.clone())? {
+        // Anything but JSON would break the stream of --progress-json
+        if self.warning_summary && !self.quiet && !self.progress_json {
+            print_warning_summary(&log.warnings);
+        }
+
+        match exit {
             ExitStatus::Exited(0) => (),
-            other => bail!(ExitError(other)),
+            _ if !log.missing_features.is_empty() => {
+                if self.auto_features && !self.experimental_features {
+                    warn!("Retrying with nix-command and flakes enabled for this build");
+                    return self.with_experimental_features().exec();
+                }
+                bail!(experimental_features_hint(&log.missing_features));
+            }
+            _ if log.out_of_memory => {
+                if self.auto_recover && !self.single_job {
+                    warn!("The build ran out of memory, retrying with a single job");
+                    return Self {
+                        single_job: true,
+                        ..self.clone()
+                    }
+                    .exec();
+                }
+                bail!(OUT_OF_MEMORY_HINT);
+            }
+            other => {
+                // Best effort, the log may be gone or in a store nix log can't read
+                if let (true, Some(drv)) = (self.print_build_logs, log.failed_derivation) {
+                    if let Err(err) = nix_log_command(&drv).and_then(|cmd| cmd.exec()) {
+                        warn!("Couldn't print the log of {drv}: {err}");
+                    }
+                }
+                bail!(ExitError(other))
+            }
+        }
+
+        if let (Some(n), false) = (self.build_report, self.quiet) {
+            print_build_report(&log, n);
+        }
+
+        if self.report_hashes {
+            print_nar_hashes(&self.nar_hashes(&log.out_paths)?);
+        }
+
+        debug!(out_paths = ?log.out_paths);
+        Ok(log.out_paths)
+    }
+
+    /// NAR hash of each of `paths`, in the store that was built into
+    fn nar_hashes(&self, paths: &[PathBuf]) -> Result<BTreeMap<PathBuf, String>> {
+        if paths.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let output = CommandBuilder::default()
+            .args(self.path_info_args(paths))
+            .message("Querying the hashes of the outputs")
+            .build()?
+            .exec_capture()?
+            .unwrap_or_default();
+        parse_nar_hashes(&output)
+    }
+
+    fn path_info_args(&self, paths: &[PathBuf]) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![util::nix_bin().into(), "path-info".into()];
+        args.push("--json".into());
+        if self.experimental_features {
+            args.extend(
+                ["--extra-experimental-features", "nix-command flakes"].map(OsString::from),
+            );
+        }
+        if let Some(store) = &self.store {
+            args.extend(["--store".into(), store.into()]);
         }
+        args.extend(paths.iter().map(OsString::from));
+        args
+    }
+
+    /// Evaluates each installable to its derivation, which catches evaluation errors without
+    /// realising anything
+    fn exec_eval(&self) -> Result<Vec<PathBuf>> {
+        let mut drvs = Vec::new();
+        for flakeref in &self.flakerefs {
+            let output = CommandBuilder::default()
+                .args(self.eval_args(flakeref))
+                .message(format!("Evaluating {flakeref}"))
+                .build()?
+                .exec_capture()
+                .wrap_err_with(|| format!("Evaluating {flakeref}"))?
+                .unwrap_or_default();
+
+            let drv = parse_drv_path(&output)?;
+            println!("{}", drv.display());
+            drvs.push(drv);
+        }
+
+        Ok(drvs)
+    }
 
+    /// Prints the derivations that would be built and the paths that would be fetched
+    fn exec_plan(&self) -> Result<()> {
+        // The listing is a notice of nix, which --quiet and nom would swallow
+        let dry_run = Self {
+            nom: false,
+            quiet: false,
+            ..self.clone()
+        };
+        let mut args = dry_run.to_args();
+        args.push("--dry-run".into());
+
+        let output = CommandBuilder::default()
+            .args(args)
+            .message(format!(
+                "Planning the build of {}",
+                self.flakerefs.join(", ")
+            ))
+            .merge_stderr(true)
+            .build()?
+            .exec_capture()
+            .wrap_err("Planning the build")?
+            .unwrap_or_default();
+
+        let plan = BuildPlan::parse(&output);
+        if self.plan_paths {
+            println!("{}", plan.details());
+        } else {
+            println!("{}", plan.summary());
+        }
         Ok(())
     }
+
+    /// The same build, with the experimental features nh needs enabled
+    fn with_experimental_features(&self) -> Self {
+        Self {
+            experimental_features: true,
+            ..self.clone()
+        }
+    }
+
+    /// Runs nix with its logs going to stderr, inspecting them on the way
+    fn exec_plain(&self, args: &[OsString]) -> Result<(ExitStatus, BuildLog)> {
+        let nix = Exec::cmd(&args[0])
+            .args(&args[1..])
+            .stdout(Redirection::Pipe)
+            .stderr(self.stderr_policy.redirection());
+        debug!(?nix);
+        show_command(&[args]);
+
+        let mut nix = nix.popen()?;
+        let _tracked = signals::track(nix.pid());
+        let mut reader = BufReader::new(nix.stdout.take().wrap_err("Taking nix stdout")?);
+
+        // Drained on the side, as nix blocks once a pipe that isn't read is full
+        let captured = nix.stderr.take().map(|stderr| {
+            std::thread::spawn(move || {
+                let mut captured = Vec::new();
+                BufReader::new(stderr)
+                    .read_to_end(&mut captured)
+                    .map(|_| captured)
+            })
+        });
+
+        let mut log = BuildLog::default();
+        let mut progress = self.progress_json.then(ProgressTracker::default);
+        // Keep the logs around in quiet mode, in case the build fails
+        let mut quiet_logs = Vec::new();
+        let prefix = self.output_prefix.as_deref().unwrap_or_default().as_bytes();
+        let mut line = Vec::new();
+        loop {
+            line.extend_from_slice(prefix);
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+
+            let out_paths = log.out_paths.len();
+            let text = String::from_utf8_lossy(&line[prefix.len()..]);
+            log.observe(&text);
+
+            if let Some(progress) = &mut progress {
+                if let Some(update) = progress.observe(&text, SystemTime::now()) {
+                    writeln!(std::io::stderr(), "{}", update.to_json_line())?;
+                }
+            } else if log.out_paths.len() == out_paths {
+                if self.quiet {
+                    quiet_logs.extend_from_slice(&line);
+                } else {
+                    std::io::stderr().write_all(&line)?;
+                }
+            }
+            line.clear();
+        }
+
+        let exit = nix.wait()?;
+        if self.quiet && !exit.success() {
+            std::io::stderr().write_all(&quiet_logs)?;
+        }
+
+        if let Some(captured) = captured {
+            let captured = captured
+                .join()
+                .map_err(|_| eyre!("Reading nix stderr panicked"))??;
+            for line in String::from_utf8_lossy(&captured).lines() {
+                log.observe(line);
+            }
+            if !exit.success() {
+                write_prefixed(std::io::stderr(), &captured, self.output_prefix.as_deref())?;
+            }
+        }
+
+        Ok((exit, log))
+    }
+
+    /// Runs nix piped into nom, inspecting the internal-json stream on the way
+    ///
+    /// nix logs its events to stderr and prints the out paths to stdout. Only the events go to
+    /// nom, as the other lines, like the output of a misbehaving builder, can confuse it.
+    fn exec_nom(&self, args: &[OsString]) -> Result<(ExitStatus, BuildLog)> {
+        let nix = Exec::cmd(&args[0])
+            .args(&args[1..])
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe);
+        let nom_args = ["nom", "--json"].map(OsString::from);
+        let nom = Exec::cmd(&nom_args[0])
+            .args(&nom_args[1..])
+            .stdin(Redirection::Pipe)
+            .stdout(Redirection::None);
+        debug!(?nix, ?nom);
+        show_command(&[args, &nom_args]);
+
+        let mut nix = nix.popen()?;
+        let _nix_tracked = signals::track(nix.pid());
+        let mut nom = nom.popen()?;
+        let _nom_tracked = signals::track(nom.pid());
+
+        let stdout = nix.stdout.take().wrap_err("Taking nix stdout")?;
+        let reader = BufReader::new(nix.stderr.take().wrap_err("Taking nix stderr")?);
+        let writer = nom.stdin.take().wrap_err("Taking nom stdin")?;
+
+        // Drained on the side, as nix blocks once a pipe that isn't read is full
+        let out_paths = std::thread::spawn(move || {
+            let mut output = String::new();
+            BufReader::new(stdout)
+                .read_to_string(&mut output)
+                .map(|_| output)
+        });
+
+        let mut trace = match &self.trace_file {
+            Some(path) => Some(
+                std::fs::File::create(path)
+                    .wrap_err_with(|| format!("Creating trace file {path:?}"))?,
+            ),
+            None => None,
+        };
+
+        let mut log = BuildLog::default();
+        relay(reader, writer, std::io::stderr(), &mut log, trace.as_mut())?;
+
+        let out_paths = out_paths
+            .join()
+            .map_err(|_| eyre!("Reading nix stdout panicked"))??;
+        for line in out_paths.lines() {
+            log.observe(line);
+        }
+
+        let nix_exit = nix.wait()?;
+        let nom_exit = nom.wait()?;
+
+        if nix_exit.success() {
+            Ok((nom_exit, log))
+        } else {
+            Ok((nix_exit, log))
+        }
+    }
+}
+
+/// Writes `output` to `out`, with `prefix` in front of every line if there is one
+fn write_prefixed<W: Write>(mut out: W, output: &[u8], prefix: Option<&str>) -> Result<()> {
+    match prefix {
+        Some(prefix) => {
+            let mut writer = prefix::PrefixWriter::new(out, prefix);
+            writer.write_all(output)?;
+            writer.finish()?;
+        }
+        None => out.write_all(output)?,
+    }
+    Ok(())
+}
+
+/// Copies the events of nix's stderr into nom and the other lines to `stray`, recording
+/// everything into `log` and the events into `trace`
+fn relay<R: BufRead, W: Write, S: Write, T: Write>(
+    mut reader: R,
+    mut nom: W,
+    mut stray: S,
+    log: &mut BuildLog,
+    mut trace: Option<T>,
+) -> Result<()> {
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? != 0 {
+        let text = String::from_utf8_lossy(&line);
+        log.observe(&text);
+
+        match internal_json::payload(&text) {
+            Some(event) => {
+                if let Some(trace) = &mut trace {
+                    writeln!(trace, "{event}").wrap_err("Writing to the trace file")?;
+                }
+                nom.write_all(&line).wrap_err("Writing to nom")?;
+            }
+            None => stray.write_all(&line)?,
+        }
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Repeats the warnings that scrolled by during the build
+fn print_warning_summary(warnings: &[String]) {
+    use owo_colors::OwoColorize;
+
+    if warnings.is_empty() {
+        return;
+    }
+
+    eprintln!();
+    eprintln!("{}", format!("Warnings ({}):", warnings.len()).bold());
+    for warning in warnings {
+        eprintln!("- {}", warning.yellow());
+    }
+}
+
+fn print_build_report(log: &BuildLog, n: usize) {
+    use owo_colors::OwoColorize;
+
+    let slowest = log.slowest(n);
+    if slowest.is_empty() {
+        return;
+    }
+
+    eprintln!();
+    eprintln!("{}", "Slowest builds".bold());
+    for (drv, duration) in slowest {
+        let duration = Duration::from_secs(duration.as_secs());
+        eprintln!("- {} {}", humantime::format_duration(duration).green(), drv);
+    }
+}
+
+fn print_nar_hashes(hashes: &BTreeMap<PathBuf, String>) {
+    use owo_colors::OwoColorize;
+
+    if hashes.is_empty() {
+        return;
+    }
+
+    eprintln!();
+    eprintln!("{}", "Output hashes".bold());
+    for (path, hash) in hashes {
+        eprintln!("{} {}", path.display(), hash.green());
+    }
+}
+
+/// Writes the output paths of a build one per line, which is all a script capturing them reads
+pub fn print_out_paths<W: Write>(mut out: W, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        writeln!(out, "{}", path.display())?;
+    }
+    Ok(())
+}
+
+/// Prints the build log of a derivation
+fn nix_log_command(drv: &str) -> Result<Command> {
+    Ok(CommandBuilder::default()
+        .args([util::nix_bin(), "log", drv])
+        .message(format!("Printing build log of {drv}"))
+        .build()?)
 }
 
 #[derive(Debug, Error)]
 #[error("Command exited with status {0:?}")]
 pub struct ExitError(ExitStatus);
 
-pub fn edit(flakeref: FlakeRef) -> Result<()> {
-    let editor = std::env::var("EDITOR").expect("EDITOR not set");
-    edit_with(flakeref, editor)
+impl ExitError {
+    /// Exit code of the command, found anywhere in the chain of `err`
+    pub fn code_of(err: &color_eyre::Report) -> Option<u32> {
+        err.chain()
+            .find_map(|cause| match cause.downcast_ref::<Self>()? {
+                ExitError(ExitStatus::Exited(code)) => Some(*code),
+                _ => None,
+            })
+    }
 }
 
-pub fn edit_with(flakeref: FlakeRef, editor: String) -> Result<()> {
-    let mut pieces: Vec<&str> = flakeref.split('/').collect();
-    let mut final_piece: &str = pieces.remove(pieces.len() - 1);
-    final_piece = final_piece.split('#').next().unwrap();
-    pieces.push(final_piece);
+/// Output of `nix eval --raw <installable>.drvPath`
+fn parse_drv_path(output: &str) -> Result<PathBuf> {
+    let drv = output.trim();
+    if !drv.starts_with("/nix/store/") || !drv.ends_with(".drv") {
+        bail!("nix eval returned {drv:?} instead of a derivation");
+    }
+    Ok(PathBuf::from(drv))
+}
 
-    let flakedir = pieces.join("/");
+/// Path to NAR hash mapping from `nix path-info --json`
+fn parse_nar_hashes(output: &str) -> Result<BTreeMap<PathBuf, String>> {
+    Ok(util::path_info_entries(output)?
+        .into_iter()
+        .filter_map(|(path, info)| Some((path, info.get("narHash")?.as_str()?.to_string())))
+        .collect())
+}
 
-    Exec::cmd(editor)
-        .args(&vec!["."])
+const OUT_OF_MEMORY_HINT: &str = "The build seems to have run out of memory. Try lowering --max-jobs or --cores, adding swap, or pass --auto-recover to retry with a single job";
+
+/// Replaces nix's error about disabled experimental features
+fn experimental_features_hint(features: &[String]) -> String {
+    format!(
+        "The experimental nix features {} are disabled. Add \"experimental-features = nix-command flakes\" to nix.conf, or pass --auto-features to enable them for a single build",
+        features.join(", ")
+    )
+}
+
+pub fn edit(flakeref: FlakeRef, dry: bool) -> Result<()> {
+    let Ok(editor) = std::env::var("EDITOR") else {
+        bail!("EDITOR not set");
+    };
+    edit_with(flakeref, editor, dry)
+}
+
+pub fn edit_with(flakeref: FlakeRef, editor: String, dry: bool) -> Result<()> {
+    edit_command(&flakeref, &editor, dry)?.exec()
+}
+
+fn edit_command(flakeref: &FlakeRef, editor: &str, dry: bool) -> Result<Command> {
+    let flakedir = match flakeref.kind() {
+        FlakeKind::Local(dir) => dir,
+        FlakeKind::Registry(id) => bail!(
+            "{} goes through the flake registry entry {id}, so there is no local directory to edit. Pass the path of the flake instead",
+            flakeref.0
+        ),
+        FlakeKind::Remote => bail!(
+            "{} isn't on this machine, so there is no local directory to edit. Pass the path of a checkout instead",
+            flakeref.0
+        ),
+    };
+
+    Ok(CommandBuilder::default()
+        .args([editor, "."])
+        .message(format!("Opening {editor} in {}", flakedir.display()))
         .cwd(flakedir)
-        .stderr(Redirection::None)
-        .stdout(Redirection::None)
-        .join()?;
+        .dry(dry)
+        .build()?)
+}
 
-    Ok(())
+#[test]
+fn test_print_build_logs_flag() {
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(["--impure"])
+        .nom(false)
+        .print_build_logs(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "-L",
+            "--impure"
+        ]
+    );
+
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .build()
+        .unwrap();
+    assert!(!cmd.to_args().contains(&"-L".into()));
+}
+
+#[test]
+fn test_remote_store_flags() {
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(["--impure"])
+        .nom(false)
+        .builders_use_substitutes(true)
+        .store(Some(String::from("ssh-ng://builder")))
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "--builders-use-substitutes",
+            "--store",
+            "ssh-ng://builder",
+            "--impure"
+        ]
+    );
+
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        ["nix", "build", ".#foo", "--print-out-paths"]
+    );
+
+    assert!(BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .store(Some(String::new()))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_system_flag() {
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#packages.x86_64-linux.hello")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .system(Some(String::from("aarch64-linux")))
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#packages.aarch64-linux.hello",
+            "--print-out-paths",
+            "--system",
+            "aarch64-linux"
+        ]
+    );
+}
+
+#[test]
+fn test_lock_file_flags() {
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .recreate_lock_file(true)
+        .refresh(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "--recreate-lock-file",
+            "--refresh"
+        ]
+    );
+    assert_eq!(
+        cmd.warnings(),
+        ["--recreate-lock-file will overwrite flake.lock with freshly locked inputs"]
+    );
+
+    let cmd = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .refresh(true)
+        .build()
+        .unwrap();
+    assert!(cmd.warnings().is_empty());
+}
+
+#[test]
+fn test_derived_message() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .build()
+        .unwrap();
+    assert_eq!(cmd.message(), "Building .#foo");
+
+    let cmd = BuildCommandBuilder::default()
+        .message("Building NixOS configuration")
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .build()
+        .unwrap();
+    assert_eq!(cmd.message(), "Building NixOS configuration");
+}
+
+#[test]
+fn test_multiple_flakerefs() {
+    let cmd = BuildCommandBuilder::default()
+        .flakerefs([".#packages.x86_64-linux.foo", ".#packages.x86_64-linux.bar"])
+        .flakeref(r#".#nixosConfigurations."host".config.system.build.toplevel"#)
+        .extra_args(["--no-link"])
+        .system(Some(String::from("aarch64-linux")))
+        .nom(false)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#packages.aarch64-linux.foo",
+            ".#packages.aarch64-linux.bar",
+            r#".#nixosConfigurations."host".config.system.build.toplevel"#,
+            "--print-out-paths",
+            "--system",
+            "aarch64-linux",
+            "--no-link",
+        ]
+    );
+    assert_eq!(
+        cmd.message(),
+        r#"Building .#packages.x86_64-linux.foo, .#packages.x86_64-linux.bar, .#nixosConfigurations."host".config.system.build.toplevel"#
+    );
+
+    assert!(BuildCommandBuilder::default()
+        .flakerefs(Vec::<String>::new())
+        .extra_args(Vec::<String>::new())
+        .nom(false)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_quiet_build() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(Vec::<String>::new())
+        .nom(true)
+        .quiet(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        ["nix", "build", ".#foo", "--print-out-paths", "--quiet"]
+    );
+}
+
+#[test]
+fn test_nix_log_from_failed_build() {
+    let line = r#"@nix {"action":"msg","level":0,"msg":"error: builder for '/nix/store/abc-hello-2.12.drv' failed with exit code 1"}"#;
+    let mut log = BuildLog::default();
+    log.observe(line);
+    let drv = log.failed_derivation.unwrap();
+
+    assert_eq!(
+        nix_log_command(&drv).unwrap().to_args(),
+        ["nix", "log", "/nix/store/abc-hello-2.12.drv"]
+    );
+}
+
+#[test]
+fn test_experimental_features() {
+    let mut log = BuildLog::default();
+    log.observe("error: experimental Nix feature 'nix-command' is disabled; add '--extra-experimental-features nix-command' to enable it");
+    assert_eq!(
+        experimental_features_hint(&log.missing_features),
+        "The experimental nix features nix-command are disabled. Add \"experimental-features = nix-command flakes\" to nix.conf, or pass --auto-features to enable them for a single build"
+    );
+
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(false)
+        .auto_features(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.with_experimental_features().to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "--no-link",
+        ]
+    );
+}
+
+#[test]
+fn test_out_of_memory_retry() {
+    let mut log = BuildLog::default();
+    log.observe("error: builder for '/nix/store/abc-chromium.drv' failed due to signal 9 (Killed)");
+    assert!(log.out_of_memory);
+    assert!(OUT_OF_MEMORY_HINT.contains("--max-jobs"));
+
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--max-jobs", "8"])
+        .nom(false)
+        .auto_recover(true)
+        .build()
+        .unwrap();
+    let retry = BuildCommand {
+        single_job: true,
+        ..cmd
+    };
+    assert_eq!(
+        retry.to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "--max-jobs",
+            "8",
+            "--max-jobs",
+            "1",
+        ]
+    );
+}
+
+#[test]
+fn test_build_only_stdout() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(false)
+        .quiet(false)
+        .build()
+        .unwrap();
+
+    // Stands in for nix, which logs to stderr and prints the out path to stdout
+    let nix = [
+        "sh",
+        "-c",
+        "echo 'building /nix/store/abc-foo.drv' >&2; echo /nix/store/abc-foo",
+    ]
+    .map(OsString::from);
+    let (exit, log) = cmd.exec_plain(&nix).unwrap();
+    assert!(exit.success());
+
+    let mut stdout = Vec::new();
+    print_out_paths(&mut stdout, &log.out_paths).unwrap();
+    assert_eq!(String::from_utf8(stdout).unwrap(), "/nix/store/abc-foo\n");
+}
+
+#[test]
+fn test_prefixed_build_logs() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(true)
+        .quiet(false)
+        .output_prefix(Some(String::from("[hostA] ")))
+        .build()
+        .unwrap();
+    // nom would draw its own screen, which can't be prefixed
+    assert!(!cmd.use_nom());
+
+    let nix = [
+        "sh",
+        "-c",
+        "echo 'building foo' >&2; echo /nix/store/abc-foo",
+    ]
+    .map(OsString::from);
+    let (_, log) = cmd.exec_plain(&nix).unwrap();
+    assert_eq!(log.out_paths, [PathBuf::from("/nix/store/abc-foo")]);
+}
+
+#[test]
+fn test_eval_only() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref("/flake#nixosConfigurations.\"host\".config.system.build.toplevel")
+        .extra_args(["--impure"])
+        .nom(true)
+        .eval_only(true)
+        .read_only_lock_file(true)
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        cmd.eval_args(&cmd.flakerefs[0]),
+        [
+            "nix",
+            "eval",
+            "--raw",
+            "/flake#nixosConfigurations.\"host\".config.system.build.toplevel.drvPath",
+            "--no-write-lock-file",
+            "--no-update-lock-file",
+            "--impure",
+        ]
+    );
+
+    assert_eq!(
+        parse_drv_path("/nix/store/abc-nixos-system-host.drv\n").unwrap(),
+        PathBuf::from("/nix/store/abc-nixos-system-host.drv")
+    );
+    assert!(parse_drv_path("").is_err());
+    assert!(parse_drv_path("/nix/store/abc-nixos-system-host").is_err());
+}
+
+#[test]
+fn test_builder_template() {
+    let mut base = CommandBuilder::default();
+    base.args(["nix", "store"])
+        .dry(true)
+        .message("Querying the store");
+
+    let commands: Vec<_> = ["/nix/store/aaa", "/nix/store/bbb"]
+        .iter()
+        .map(|path| base.clone().args(["ls", path]).build().unwrap())
+        .collect();
+    assert_eq!(
+        commands[0].to_args(),
+        ["nix", "store", "ls", "/nix/store/aaa"]
+    );
+    assert_eq!(
+        commands[1].to_args(),
+        ["nix", "store", "ls", "/nix/store/bbb"]
+    );
+    assert!(commands.iter().all(|cmd| cmd.dry));
+    assert_eq!(base.build().unwrap().to_args(), ["nix", "store"]);
+
+    // Reusing the builder itself accumulates
+    base.args(["ping"]);
+    let cmd = base.build().unwrap();
+    assert_eq!(cmd.to_args(), ["nix", "store", "ping"]);
+    assert_eq!(cmd.message.as_deref(), Some("Querying the store"));
+}
+
+#[test]
+fn test_exec_stream() {
+    let cmd = CommandBuilder::default()
+        .args(["seq", "1", "500000"])
+        .build()
+        .unwrap();
+
+    // Only a line at a time is held by the caller
+    let mut sum = 0u64;
+    let mut longest = 0;
+    let lines = cmd
+        .exec_stream(|line| {
+            longest = longest.max(line.len());
+            sum += line.parse::<u64>()?;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(lines, Some(500000));
+    assert_eq!(sum, 500000 * 500001 / 2);
+    assert_eq!(longest, "500000".len());
+
+    // The callback can stop the stream early
+    let mut seen = 0;
+    let result = cmd.exec_stream(|_| {
+        seen += 1;
+        if seen == 10 {
+            bail!("enough");
+        }
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert_eq!(seen, 10);
+
+    let failing = CommandBuilder::default()
+        .args(["sh", "-c", "echo partial; exit 3"])
+        .build()
+        .unwrap();
+    assert!(failing.exec_stream(|_| Ok(())).is_err());
+}
+
+#[test]
+fn test_trace_file() {
+    let output = concat!(
+        r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building '/nix/store/aaa-foo.drv'","fields":["/nix/store/aaa-foo.drv","",1,1]}"#,
+        "\n",
+        r#"@nix {"action":"stop","id":1}"#,
+        "\n",
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace.jsonl");
+    let mut nom = Vec::new();
+    let mut log = BuildLog::default();
+    relay(
+        output.as_bytes(),
+        &mut nom,
+        std::io::sink(),
+        &mut log,
+        Some(std::fs::File::create(&path).unwrap()),
+    )
+    .unwrap();
+
+    assert_eq!(nom, output.as_bytes());
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    let events: Vec<serde_json::Value> = trace
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["action"], "start");
+    assert_eq!(events[1]["action"], "stop");
+}
+
+#[test]
+fn test_nom_streams() {
+    let stderr = concat!(
+        r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building '/nix/store/aaa-foo.drv'","fields":["/nix/store/aaa-foo.drv","",1,1]}"#,
+        "\n",
+        "warning: Git tree '/etc/nixos' is dirty\n",
+        "builder printed this {not json\n",
+        r#"@nix {"action":"stop","id":1}"#,
+        "\n",
+    );
+
+    let mut nom = Vec::new();
+    let mut stray = Vec::new();
+    let mut log = BuildLog::default();
+    relay(
+        stderr.as_bytes(),
+        &mut nom,
+        &mut stray,
+        &mut log,
+        None::<std::io::Sink>,
+    )
+    .unwrap();
+
+    // Only the events reach nom, the rest goes to the terminal
+    let nom = String::from_utf8(nom).unwrap();
+    assert_eq!(nom.lines().count(), 2);
+    assert!(nom.lines().all(|line| line.starts_with("@nix ")));
+    assert_eq!(
+        String::from_utf8(stray).unwrap(),
+        "warning: Git tree '/etc/nixos' is dirty\nbuilder printed this {not json\n"
+    );
+    // nh still sees the warnings
+    assert_eq!(log.warnings, ["Git tree '/etc/nixos' is dirty"]);
+}
+
+#[test]
+fn test_shell_join() {
+    assert_eq!(
+        shell_join(&["nix", "build", ".#foo", "--out-link", "/tmp/nh-os/result"]),
+        "nix build .#foo --out-link /tmp/nh-os/result"
+    );
+    assert_eq!(
+        shell_join(&[
+            "nix",
+            "eval",
+            "/etc/nixos#nixosConfigurations",
+            "--apply",
+            r#" x: x ? "host" "#
+        ]),
+        r#"nix eval /etc/nixos#nixosConfigurations --apply ' x: x ? "host" '"#
+    );
+    assert_eq!(
+        shell_join(&["sh", "-c", "echo 'it''s' $HOME", ""]),
+        r#"sh -c 'echo '\''it'\'''\''s'\'' $HOME' ''"#
+    );
+}
+
+#[test]
+fn test_edit_dry() {
+    let cmd = edit_command(&FlakeRef::from("/etc/nixos#myhost"), "my-editor", true).unwrap();
+    assert_eq!(cmd.to_args(), ["my-editor", "."]);
+    assert_eq!(cmd.cwd, Some(PathBuf::from("/etc/nixos")));
+    assert_eq!(
+        cmd.message.as_deref(),
+        Some("Opening my-editor in /etc/nixos")
+    );
+
+    // Would fail to spawn if it was actually run
+    edit_with(
+        FlakeRef::from("/etc/nixos#myhost"),
+        String::from("/nonexistent/editor"),
+        true,
+    )
+    .unwrap();
+
+    // Only local flakes can be edited
+    let err = edit_command(&FlakeRef::from("nixpkgs#hello"), "my-editor", true).unwrap_err();
+    assert!(err.to_string().contains("flake registry entry nixpkgs"));
+    assert!(edit_command(&FlakeRef::from("github:o/r#a"), "my-editor", true).is_err());
+}
+
+#[test]
+fn test_nar_hashes() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(false)
+        .store(Some(String::from("/mnt")))
+        .report_hashes(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        cmd.path_info_args(&[PathBuf::from("/nix/store/aaa-foo")]),
+        [
+            "nix",
+            "path-info",
+            "--json",
+            "--store",
+            "/mnt",
+            "/nix/store/aaa-foo"
+        ]
+    );
+
+    let expected = BTreeMap::from([
+        (
+            PathBuf::from("/nix/store/aaa-foo"),
+            String::from("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+        ),
+        (
+            PathBuf::from("/nix/store/bbb-foo-man"),
+            String::from("sha256:1b4sb93wp679q4zx9k1ignby1yna3z7c4c2ri3wphylbc2dwsys0"),
+        ),
+    ]);
+
+    // nix 2.19 and later
+    let keyed = r#"{
+        "/nix/store/aaa-foo": { "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=", "narSize": 120 },
+        "/nix/store/bbb-foo-man": { "narHash": "sha256:1b4sb93wp679q4zx9k1ignby1yna3z7c4c2ri3wphylbc2dwsys0", "narSize": 8 },
+        "/nix/store/ccc-missing": null
+    }"#;
+    assert_eq!(parse_nar_hashes(keyed).unwrap(), expected);
+
+    // Older versions
+    let listed = r#"[
+        { "path": "/nix/store/bbb-foo-man", "narHash": "sha256:1b4sb93wp679q4zx9k1ignby1yna3z7c4c2ri3wphylbc2dwsys0" },
+        { "path": "/nix/store/aaa-foo", "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=" },
+        { "path": "/nix/store/ccc-missing", "valid": false }
+    ]"#;
+    assert_eq!(parse_nar_hashes(listed).unwrap(), expected);
+
+    assert!(parse_nar_hashes("").is_err());
+    assert!(parse_nar_hashes("42").is_err());
+}
+
+#[test]
+fn test_stderr_policy() {
+    let build = |policy| {
+        BuildCommandBuilder::default()
+            .flakeref(".#foo")
+            .extra_args(["--no-link"])
+            .nom(false)
+            .quiet(false)
+            .output_prefix(None)
+            .stderr_policy(policy)
+            .build()
+            .unwrap()
+    };
+    let default = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(false)
+        .build()
+        .unwrap();
+    assert_eq!(default.stderr_policy, StderrPolicy::Merge);
+
+    assert!(matches!(
+        StderrPolicy::Merge.redirection(),
+        Redirection::Merge
+    ));
+    assert!(matches!(
+        StderrPolicy::Inherit.redirection(),
+        Redirection::None
+    ));
+    assert!(matches!(
+        StderrPolicy::Capture.redirection(),
+        Redirection::Pipe
+    ));
+
+    let nix = [
+        "sh",
+        "-c",
+        "echo 'warning: Git tree is dirty' >&2; echo /nix/store/abc-foo",
+    ]
+    .map(OsString::from);
+
+    // nh only sees the warnings of nix if stderr goes through it
+    for (policy, warnings) in [
+        (StderrPolicy::Merge, 1),
+        (StderrPolicy::Inherit, 0),
+        (StderrPolicy::Capture, 1),
+    ] {
+        let (exit, log) = build(policy).exec_plain(&nix).unwrap();
+        assert!(exit.success());
+        assert_eq!(log.out_paths, [PathBuf::from("/nix/store/abc-foo")]);
+        assert_eq!(log.warnings.len(), warnings, "{policy:?}");
+    }
+
+    // Captured stderr is drained while nix runs, so more than a pipe buffer of it doesn't block
+    let chatty = [
+        "sh",
+        "-c",
+        "i=0; while [ $i -lt 20000 ]; do echo \"building line $i\" >&2; i=$((i+1)); done; echo /nix/store/abc-foo",
+    ]
+    .map(OsString::from);
+    let (exit, log) = build(StderrPolicy::Capture).exec_plain(&chatty).unwrap();
+    assert!(exit.success());
+    assert_eq!(log.out_paths, [PathBuf::from("/nix/store/abc-foo")]);
+
+    // Printed on failure with the prefix, like the rest of the logs
+    let mut output = Vec::new();
+    write_prefixed(
+        &mut output,
+        b"error: builder failed\nexit 1",
+        Some("[x86_64-linux] "),
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "[x86_64-linux] error: builder failed\n[x86_64-linux] exit 1\n"
+    );
+    let mut output = Vec::new();
+    write_prefixed(&mut output, b"error: builder failed\n", None).unwrap();
+    assert_eq!(output, b"error: builder failed\n");
+}
+
+#[test]
+fn test_progress_json_args() {
+    let cmd = BuildCommandBuilder::default()
+        .flakeref(".#foo")
+        .extra_args(["--no-link"])
+        .nom(true)
+        .quiet(false)
+        .output_prefix(None)
+        .progress_json(true)
+        .build()
+        .unwrap();
+    // nom would draw over the JSON lines
+    assert!(!cmd.use_nom());
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            ".#foo",
+            "--print-out-paths",
+            "--log-format",
+            "internal-json",
+            "--verbose",
+            "--no-link"
+        ]
+    );
 }
@@ -0,0 +1,252 @@
+//! Defaults for the command line, from a global and a flake-local config file
+//!
+//! Precedence, highest first: the command line, `.nh.toml` at the root of the flake,
+//! `$XDG_CONFIG_HOME/nh/config.toml`, and the built-in defaults.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::elevate::ElevationProgram;
+use crate::flake;
+use crate::interface::{FlakeRef, NomMode};
+
+/// Name of the flake-local config file, next to flake.nix
+pub const LOCAL_CONFIG: &str = ".nh.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Configuration to build instead of the one named after the hostname
+    pub hostname: Option<String>,
+    pub nom: Option<NomMode>,
+    /// Arguments passed to nix before the ones of the command line
+    pub extra_args: Option<Vec<String>>,
+    /// Only read from the global config, as nh needs it before knowing the flake
+    pub elevation_program: Option<ElevationProgram>,
+    /// Only read from the global config, like elevation-program
+    pub askpass: Option<PathBuf>,
+    /// ntfy topic for --notify, as a URL or a topic name on ntfy.sh. Only read from the global
+    /// config
+    pub ntfy_topic: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file at `path`, which may not exist
+    pub fn read(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).wrap_err_with(|| format!("Parsing {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).wrap_err_with(|| format!("Reading {path:?}")),
+        }
+    }
+
+    /// The config of `$XDG_CONFIG_HOME/nh/config.toml`
+    pub fn global() -> Result<Self> {
+        match global_path(
+            std::env::var_os("XDG_CONFIG_HOME"),
+            std::env::var_os("HOME"),
+        ) {
+            Some(path) => Self::read(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The global config merged under the one of the flake, if it's local
+    pub fn load(flakeref: &FlakeRef) -> Result<Self> {
+        let global = Self::global()?;
+
+        let local = match flake::local_dir(flakeref)
+            .and_then(|dir| std::fs::canonicalize(dir).ok())
+            .and_then(|dir| find_local(&dir))
+        {
+            Some(path) => {
+                debug!("Using {path:?}");
+                Self::read(&path)?.without_global_only(&path)
+            }
+            None => Self::default(),
+        };
+
+        Ok(local.over(global))
+    }
+
+    /// `self` without the settings that only the global config may set, warning about each
+    ///
+    /// They pick what runs as root, which a checkout of someone else's flake must not choose.
+    fn without_global_only(mut self, path: &Path) -> Self {
+        let ignored = [
+            ("elevation-program", self.elevation_program.take().is_some()),
+            ("askpass", self.askpass.take().is_some()),
+            ("ntfy-topic", self.ntfy_topic.take().is_some()),
+        ];
+        for (key, _) in ignored.iter().filter(|(_, set)| *set) {
+            warn!("Ignoring {key} in {path:?}, it's only read from the global config");
+        }
+        self
+    }
+
+    /// The settings of `self`, falling back to `lower` for the unset ones
+    pub fn over(self, lower: Self) -> Self {
+        Self {
+            hostname: self.hostname.or(lower.hostname),
+            nom: self.nom.or(lower.nom),
+            extra_args: self.extra_args.or(lower.extra_args),
+            elevation_program: self.elevation_program.or(lower.elevation_program),
+            askpass: self.askpass.or(lower.askpass),
+            ntfy_topic: self.ntfy_topic.or(lower.ntfy_topic),
+        }
+    }
+
+    pub fn extra_args(&self) -> &[String] {
+        self.extra_args.as_deref().unwrap_or_default()
+    }
+}
+
+/// `.nh.toml` at the root of the flake containing `dir`, if there is one
+pub fn find_local(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| dir.join("flake.nix").is_file())
+        .map(|root| root.join(LOCAL_CONFIG))
+        .filter(|path| path.is_file())
+}
+
+/// `$XDG_CONFIG_HOME/nh/config.toml`, falling back to `~/.config/nh/config.toml`
+fn global_path(xdg_config_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    let config_home = match (xdg_config_home, home) {
+        // Relative paths are invalid according to the spec
+        (Some(dir), _) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        (_, Some(home)) => PathBuf::from(home).join(".config"),
+        (_, None) => return None,
+    };
+    Some(config_home.join("nh").join("config.toml"))
+}
+
+/// The value of the command line if given, else the one of the config, else `default`
+pub fn pick<T>(cli: Option<T>, config: Option<T>, default: T) -> T {
+    cli.or(config).unwrap_or(default)
+}
+
+#[test]
+fn test_find_local() {
+    let flake = tempfile::tempdir().unwrap();
+    let subdir = flake.path().join("hosts/laptop");
+    std::fs::create_dir_all(&subdir).unwrap();
+    std::fs::write(flake.path().join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+
+    // No config yet
+    assert_eq!(find_local(&subdir), None);
+
+    std::fs::write(flake.path().join(LOCAL_CONFIG), "nom = \"never\"").unwrap();
+    let expected = Some(flake.path().join(LOCAL_CONFIG));
+    assert_eq!(find_local(flake.path()), expected);
+    assert_eq!(find_local(&subdir), expected);
+
+    // A nested flake is its own root, even without a config
+    std::fs::write(subdir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    assert_eq!(find_local(&subdir), None);
+
+    assert_eq!(
+        global_path(Some("/config".into()), Some("/home/user".into())),
+        Some(PathBuf::from("/config/nh/config.toml"))
+    );
+    assert_eq!(
+        global_path(None, Some("/home/user".into())),
+        Some(PathBuf::from("/home/user/.config/nh/config.toml"))
+    );
+    assert_eq!(global_path(None, None), None);
+}
+
+#[test]
+fn test_config_precedence() {
+    let global: Config = toml::from_str(
+        r#"
+        hostname = "global"
+        nom = "always"
+        extra-args = ["--option", "cores", "4"]
+        elevation-program = "doas"
+        askpass = "/usr/bin/ssh-askpass"
+        ntfy-topic = "my-builds"
+        "#,
+    )
+    .unwrap();
+    let local: Config = toml::from_str(
+        r#"
+        nom = "never"
+        extra-args = ["--impure"]
+        "#,
+    )
+    .unwrap();
+    assert!(toml::from_str::<Config>("unknown = 1").is_err());
+
+    let config = local.over(global.clone());
+    assert_eq!(config.hostname.as_deref(), Some("global"));
+    assert_eq!(config.nom, Some(NomMode::Never));
+    assert_eq!(config.extra_args(), ["--impure"]);
+    assert_eq!(config.elevation_program, Some(ElevationProgram::Doas));
+    assert_eq!(
+        config.askpass.as_deref(),
+        Some(Path::new("/usr/bin/ssh-askpass"))
+    );
+    assert_eq!(config.ntfy_topic.as_deref(), Some("my-builds"));
+
+    // The command line wins over the local config, which wins over the global one
+    assert_eq!(
+        pick(Some(NomMode::Auto), config.nom, NomMode::Auto),
+        NomMode::Auto
+    );
+    assert_eq!(pick(None, config.nom, NomMode::Auto), NomMode::Never);
+    assert_eq!(pick(None, global.nom, NomMode::Auto), NomMode::Always);
+    assert_eq!(
+        pick(None, Config::default().nom, NomMode::Auto),
+        NomMode::Auto
+    );
+    assert_eq!(
+        pick(
+            Some(String::from("cli")),
+            config.hostname.clone(),
+            String::new()
+        ),
+        "cli"
+    );
+    assert_eq!(pick(None, config.hostname, String::new()), "global");
+}
+
+#[test]
+fn test_local_config_global_only() {
+    let flake = tempfile::tempdir().unwrap();
+    std::fs::write(flake.path().join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    std::fs::write(
+        flake.path().join(LOCAL_CONFIG),
+        r#"
+        nom = "never"
+        elevation-program = "doas"
+        askpass = "/tmp/evil-askpass"
+        ntfy-topic = "someone-elses-topic"
+        "#,
+    )
+    .unwrap();
+
+    let path = find_local(flake.path()).unwrap();
+    let local = Config::read(&path).unwrap().without_global_only(&path);
+    assert_eq!(
+        local,
+        Config {
+            nom: Some(NomMode::Never),
+            ..Config::default()
+        }
+    );
+
+    // So the global settings stay in effect
+    let global = Config {
+        askpass: Some(PathBuf::from("/usr/bin/ssh-askpass")),
+        ..Config::default()
+    };
+    assert_eq!(
+        local.over(global).askpass.as_deref(),
+        Some(Path::new("/usr/bin/ssh-askpass"))
+    );
+}
@@ -0,0 +1,134 @@
+use std::{convert::Infallible, ffi::OsStr, ffi::OsString, str::FromStr};
+
+use color_eyre::Result;
+use tracing::warn;
+
+use crate::{commands, util};
+
+/// Program used to compare two closures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffTool {
+    /// `nvd diff`
+    Nvd,
+    /// `nix store diff-closures`
+    NixStoreDiffClosures,
+    /// A custom program, which receives both store paths as arguments
+    Custom(OsString),
+}
+
+impl FromStr for DiffTool {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_ascii_whitespace().collect();
+
+        Ok(match words.as_slice() {
+            ["nvd"] | ["nvd", "diff"] => Self::Nvd,
+            ["nix", "store", "diff-closures"] => Self::NixStoreDiffClosures,
+            _ => Self::Custom(s.into()),
+        })
+    }
+}
+
+impl DiffTool {
+    /// Falls back to `nix store diff-closures` if nvd isn't installed
+    pub fn resolve(&self) -> Self {
+        match self {
+            Self::Nvd if !util::in_path("nvd") => {
+                warn!("nvd not found, falling back to nix store diff-closures");
+                Self::NixStoreDiffClosures
+            }
+            other => other.clone(),
+        }
+    }
+
+    pub fn command<A, B>(&self, from: A, to: B) -> Result<commands::Command>
+    where
+        A: AsRef<OsStr>,
+        B: AsRef<OsStr>,
+    {
+        self.command_with_color(from, to, false)
+    }
+
+    /// Like [`DiffTool::command`], with nvd keeping its colours when its output goes through nh
+    /// before reaching a terminal
+    pub fn command_with_color<A, B>(&self, from: A, to: B, color: bool) -> Result<commands::Command>
+    where
+        A: AsRef<OsStr>,
+        B: AsRef<OsStr>,
+    {
+        let program: Vec<&OsStr> = match self {
+            Self::Nvd if color => vec!["nvd".as_ref(), "--color=always".as_ref(), "diff".as_ref()],
+            Self::Nvd => vec!["nvd".as_ref(), "diff".as_ref()],
+            Self::NixStoreDiffClosures => vec![
+                util::nix_bin().as_ref(),
+                "store".as_ref(),
+                "diff-closures".as_ref(),
+            ],
+            Self::Custom(program) => vec![program.as_os_str()],
+        };
+
+        Ok(commands::CommandBuilder::default()
+            .args(program)
+            .args([from.as_ref(), to.as_ref()])
+            .message("Comparing changes")
+            .build()?)
+    }
+}
+
+#[test]
+fn test_diff_tool_args() {
+    let args = |tool: &str| {
+        tool.parse::<DiffTool>()
+            .unwrap()
+            .command("/run/current-system", "/nix/store/abc-nixos-system")
+            .unwrap()
+            .to_args()
+    };
+
+    assert_eq!(
+        args("nvd diff"),
+        [
+            "nvd",
+            "diff",
+            "/run/current-system",
+            "/nix/store/abc-nixos-system"
+        ]
+    );
+    assert_eq!(
+        args("nix store diff-closures"),
+        [
+            "nix",
+            "store",
+            "diff-closures",
+            "/run/current-system",
+            "/nix/store/abc-nixos-system"
+        ]
+    );
+    assert_eq!(
+        args("/usr/local/bin/my-diff"),
+        [
+            "/usr/local/bin/my-diff",
+            "/run/current-system",
+            "/nix/store/abc-nixos-system"
+        ]
+    );
+    assert_eq!(
+        "nix store diff-closures".parse(),
+        Ok(DiffTool::NixStoreDiffClosures)
+    );
+
+    assert_eq!(
+        DiffTool::Nvd
+            .command_with_color("/run/current-system", "/nix/store/abc-nixos-system", true)
+            .unwrap()
+            .to_args(),
+        [
+            "nvd",
+            "--color=always",
+            "diff",
+            "/run/current-system",
+            "/nix/store/abc-nixos-system"
+        ]
+    );
+}
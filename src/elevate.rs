@@ -0,0 +1,172 @@
+//! Running commands as root, through sudo, doas or run0
+
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::commands::CommandBuilder;
+
+static ELEVATION: OnceCell<Elevation> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElevationProgram {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+}
+
+impl ElevationProgram {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sudo => "sudo",
+            Self::Doas => "doas",
+            Self::Run0 => "run0",
+        }
+    }
+}
+
+/// How nh gets root for the steps that need it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elevation {
+    pub program: ElevationProgram,
+    /// Program asking for the password without a terminal, like ssh-askpass
+    pub askpass: Option<PathBuf>,
+    /// Whether the password can be typed on a terminal, in which case the askpass isn't used
+    pub interactive: bool,
+}
+
+impl Default for Elevation {
+    fn default() -> Self {
+        Self {
+            program: ElevationProgram::default(),
+            askpass: None,
+            interactive: true,
+        }
+    }
+}
+
+impl Elevation {
+    /// Elevation through `program`, using `askpass` if stdin isn't a terminal
+    pub fn new(program: ElevationProgram, askpass: Option<PathBuf>) -> Self {
+        if askpass.is_some() && program != ElevationProgram::Sudo {
+            warn!(
+                "{} has no askpass support, it will prompt on its own",
+                program.name()
+            );
+        }
+
+        Self {
+            program,
+            askpass,
+            interactive: std::io::stdin().is_terminal(),
+        }
+    }
+
+    /// Askpass to use for this invocation, only sudo supports one
+    fn active_askpass(&self) -> Option<&PathBuf> {
+        match (self.program, self.interactive) {
+            (ElevationProgram::Sudo, false) => self.askpass.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Arguments in front of the command to run as root
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args = vec![OsString::from(self.program.name())];
+        if self.active_askpass().is_some() {
+            args.push("-A".into());
+        }
+        args
+    }
+
+    /// Environment of the elevation program
+    pub fn env(&self) -> Vec<(OsString, OsString)> {
+        self.active_askpass()
+            .map(|askpass| ("SUDO_ASKPASS".into(), askpass.into()))
+            .into_iter()
+            .collect()
+    }
+
+    /// Builder of a command run as root, to add the command itself to
+    pub fn builder(&self) -> CommandBuilder {
+        let mut cmd = CommandBuilder::default();
+        cmd.args(self.args());
+        for (key, value) in self.env() {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+}
+
+/// Sets how every later command gets root, from `--elevation-program` and `--askpass`
+pub fn configure(elevation: Elevation) {
+    let _ = ELEVATION.set(elevation);
+}
+
+/// Elevation set with [`configure`], sudo without askpass otherwise
+pub fn current() -> Elevation {
+    ELEVATION.get().cloned().unwrap_or_default()
+}
+
+/// Builder of a command run as root with the configured elevation
+pub fn command() -> CommandBuilder {
+    current().builder()
+}
+
+#[test]
+fn test_askpass() {
+    let askpass = PathBuf::from("/run/current-system/sw/bin/ssh-askpass");
+    let run = |elevation: &Elevation| {
+        elevation
+            .builder()
+            .args(["systemctl", "reboot"])
+            .build()
+            .unwrap()
+    };
+
+    // Nobody is there to type the password
+    let headless = Elevation {
+        program: ElevationProgram::Sudo,
+        askpass: Some(askpass.clone()),
+        interactive: false,
+    };
+    let cmd = run(&headless);
+    assert_eq!(cmd.to_args(), ["sudo", "-A", "systemctl", "reboot"]);
+    assert_eq!(cmd.env_var("SUDO_ASKPASS"), Some(askpass.as_os_str()));
+
+    // sudo prompts on the terminal as usual
+    let interactive = Elevation {
+        interactive: true,
+        ..headless.clone()
+    };
+    let cmd = run(&interactive);
+    assert_eq!(cmd.to_args(), ["sudo", "systemctl", "reboot"]);
+    assert_eq!(cmd.env_var("SUDO_ASKPASS"), None);
+
+    // Without an askpass, nothing changes
+    let cmd = run(&Elevation {
+        askpass: None,
+        ..headless.clone()
+    });
+    assert_eq!(cmd.to_args(), ["sudo", "systemctl", "reboot"]);
+
+    for (program, name) in [
+        (ElevationProgram::Doas, "doas"),
+        (ElevationProgram::Run0, "run0"),
+    ] {
+        let cmd = run(&Elevation {
+            program,
+            ..headless.clone()
+        });
+        assert_eq!(cmd.to_args(), [name, "systemctl", "reboot"]);
+        assert_eq!(cmd.env_var("SUDO_ASKPASS"), None);
+    }
+
+    assert_eq!(Elevation::default().args(), ["sudo"]);
+}
@@ -0,0 +1,381 @@
+//! Summary of `nix store diff-closures`, grouped by the kind of change
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::commands::CommandBuilder;
+use crate::interface::{ExplainArgs, NHRunnable};
+use crate::{internal_json, util};
+
+static SIZE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([+-]\d+(?:\.\d+)?) KiB$").unwrap());
+
+/// Versions of a package on one side, which can be several, like two kernels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versions(Vec<String>);
+
+impl Versions {
+    /// Versions as diff-closures prints them, with `∅` for none and `ε` for an empty version
+    fn parse(text: &str) -> Self {
+        match text.trim() {
+            "∅" => Self(Vec::new()),
+            text => Self(
+                text.split(", ")
+                    .map(|version| match version {
+                        "ε" => String::new(),
+                        version => version.to_string(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn latest(&self) -> Option<&str> {
+        self.0
+            .iter()
+            .max_by(|a, b| compare_versions(a, b))
+            .map(String::as_str)
+    }
+}
+
+impl std::fmt::Display for Versions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "∅");
+        }
+        let versions: Vec<&str> = self
+            .0
+            .iter()
+            .map(|version| if version.is_empty() { "ε" } else { version })
+            .collect();
+        write!(f, "{}", versions.join(", "))
+    }
+}
+
+/// A line of diff-closures, like `firefox: 120.0 → 121.0, +1234.5 KiB`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub name: String,
+    pub old: Versions,
+    pub new: Versions,
+    /// Change of the closure size in bytes, if nix printed one
+    pub size: Option<i64>,
+}
+
+/// Changes of diff-closures by kind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Explanation {
+    pub added: Vec<Change>,
+    pub removed: Vec<Change>,
+    pub version_changed: Vec<Change>,
+    /// Same versions, only the size changed, like a rebuild with other dependencies
+    pub size_changed: Vec<Change>,
+}
+
+/// Parses a line of diff-closures, after stripping the colors
+fn parse_change(line: &str) -> Option<Change> {
+    let (name, rest) = line.trim().split_once(": ")?;
+
+    // The size is always last, after versions that may themselves contain ", "
+    let (versions, size) = match rest.rsplit_once(", ") {
+        Some((versions, size)) if SIZE_REGEX.is_match(size) => (Some(versions), Some(size)),
+        _ if SIZE_REGEX.is_match(rest) => (None, Some(rest)),
+        _ => (Some(rest), None),
+    };
+    let size = size.map(|size| {
+        let kib: f64 = SIZE_REGEX.captures(size).unwrap()[1].parse().unwrap();
+        (kib * 1024.0).round() as i64
+    });
+
+    let (old, new) = match versions {
+        Some(versions) => {
+            let (old, new) = versions.split_once(" → ")?;
+            (Versions::parse(old), Versions::parse(new))
+        }
+        None => (Versions(Vec::new()), Versions(Vec::new())),
+    };
+
+    Some(Change {
+        name: name.to_string(),
+        old,
+        new,
+        size,
+    })
+}
+
+impl Explanation {
+    /// Groups the output of `nix store diff-closures`, skipping the lines it doesn't know
+    pub fn parse(output: &str) -> Self {
+        let mut explanation = Self::default();
+        for change in output
+            .lines()
+            .map(internal_json::strip_ansi)
+            .filter_map(|line| parse_change(&line))
+        {
+            let group = match (change.old.is_empty(), change.new.is_empty()) {
+                (true, true) => &mut explanation.size_changed,
+                (true, false) => &mut explanation.added,
+                (false, true) => &mut explanation.removed,
+                (false, false) => &mut explanation.version_changed,
+            };
+            group.push(change);
+        }
+        explanation
+    }
+
+    /// Sum of the size changes, in bytes
+    pub fn net_size(&self) -> i64 {
+        [
+            &self.added,
+            &self.removed,
+            &self.version_changed,
+            &self.size_changed,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|change| change.size)
+        .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.version_changed.is_empty()
+            && self.size_changed.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        if self.is_empty() {
+            return String::from("No changes");
+        }
+
+        let mut text = String::new();
+        let groups = [
+            ("Added", &self.added),
+            ("Removed", &self.removed),
+            ("Version changed", &self.version_changed),
+            ("Size changed", &self.size_changed),
+        ];
+        for (title, changes) in groups {
+            if changes.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("{title} ({}):\n", changes.len()));
+            for change in changes {
+                text.push_str(&format!("  {}", change.name));
+                match (change.old.is_empty(), change.new.is_empty()) {
+                    (true, true) => (),
+                    (true, false) => text.push_str(&format!(" {}", change.new)),
+                    (false, true) => text.push_str(&format!(" {}", change.old)),
+                    (false, false) => {
+                        text.push_str(&format!(" {} → {}", change.old, change.new));
+                        let direction = match (change.old.latest(), change.new.latest()) {
+                            (Some(old), Some(new)) => compare_versions(old, new),
+                            _ => Ordering::Equal,
+                        };
+                        match direction {
+                            Ordering::Less => text.push_str(" (upgrade)"),
+                            Ordering::Greater => text.push_str(" (downgrade)"),
+                            Ordering::Equal => (),
+                        }
+                    }
+                }
+                if let Some(size) = change.size {
+                    text.push_str(&format!("  {}", format_signed_size(size)));
+                }
+                text.push('\n');
+            }
+        }
+        text.push_str(&format!(
+            "Net size change: {}",
+            format_signed_size(self.net_size())
+        ));
+        text
+    }
+}
+
+fn format_signed_size(bytes: i64) -> String {
+    if bytes < 0 {
+        util::format_size_delta(bytes.unsigned_abs(), 0)
+    } else {
+        util::format_size_delta(0, bytes.unsigned_abs())
+    }
+}
+
+/// Orders versions like nix does, which works for the ones that aren't semver too
+///
+/// Versions are split into runs of digits and of other characters, at dots and dashes. Numbers
+/// compare numerically, `pre` sorts before anything, and other words compare as strings, sorting
+/// before numbers.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (components(a), components(b));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (a, b) => {
+                let order = compare_components(a.unwrap_or(""), b.unwrap_or(""));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+        }
+    }
+}
+
+fn components(version: &str) -> impl Iterator<Item = &str> {
+    let mut rest = version;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches(['.', '-']);
+        let first = rest.chars().next()?;
+        let end = rest
+            .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != first.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (component, tail) = rest.split_at(end);
+        rest = tail;
+        Some(component)
+    })
+}
+
+fn compare_components(a: &str, b: &str) -> Ordering {
+    let number = |s: &str| s.parse::<u64>().ok();
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ if a == b => Ordering::Equal,
+        // A missing component sorts before anything but pre
+        _ if a.is_empty() => {
+            if b == "pre" {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            }
+        }
+        _ if b.is_empty() => compare_components(b, a).reverse(),
+        _ if a == "pre" => Ordering::Less,
+        _ if b == "pre" => Ordering::Greater,
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// `nix store diff-closures` between both paths
+fn diff_closures(old: &Path, new: &Path) -> Result<String> {
+    Ok(CommandBuilder::default()
+        .args([util::nix_bin(), "store", "diff-closures"])
+        .args([old, new])
+        .build()?
+        .exec_capture()
+        .wrap_err("Comparing the closures")?
+        .unwrap_or_default())
+}
+
+impl NHRunnable for ExplainArgs {
+    fn run(&self) -> Result<()> {
+        let output = diff_closures(&self.old, &self.new)?;
+        println!("{}", Explanation::parse(&output).to_text());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_explain() {
+    let output = "\
+\x1b[1mfirefox\x1b[0m: 120.0 → 121.0, \x1b[31;1m+1234.5 KiB\x1b[0m
+glibc: 2.38-27 → 2.38-44
+hello: ∅ → 2.12.1, +150.2 KiB
+nano: 7.2 → ∅, -200.0 KiB
+linux: 6.6.1, 6.6.2 → 6.6.3, +12.0 KiB
+openssl: 3.0.12 → 3.0.11
+zsh-theme: unstable-2024-01-02 → unstable-2023-12-24
+source: ε → 20240101
+nixos-system-host: +1.5 KiB
+";
+    let explanation = Explanation::parse(output);
+    let names = |changes: &[Change]| -> Vec<String> {
+        changes.iter().map(|change| change.name.clone()).collect()
+    };
+
+    assert_eq!(names(&explanation.added), ["hello"]);
+    assert_eq!(names(&explanation.removed), ["nano"]);
+    assert_eq!(
+        names(&explanation.version_changed),
+        [
+            "firefox",
+            "glibc",
+            "linux",
+            "openssl",
+            "zsh-theme",
+            "source"
+        ]
+    );
+    assert_eq!(names(&explanation.size_changed), ["nixos-system-host"]);
+
+    assert_eq!(
+        explanation.version_changed[2],
+        Change {
+            name: String::from("linux"),
+            old: Versions(vec![String::from("6.6.1"), String::from("6.6.2")]),
+            new: Versions(vec![String::from("6.6.3")]),
+            size: Some(12288),
+        }
+    );
+    assert_eq!(explanation.version_changed[1].size, None);
+    assert_eq!(
+        explanation.version_changed[5].old,
+        Versions(vec![String::new()])
+    );
+    assert_eq!(
+        explanation.net_size(),
+        1264128 + 153805 - 204800 + 12288 + 1536
+    );
+
+    assert_eq!(
+        explanation.to_text(),
+        "\
+Added (1):
+  hello 2.12.1  +150.2 KiB
+Removed (1):
+  nano 7.2  -200.0 KiB
+Version changed (6):
+  firefox 120.0 → 121.0 (upgrade)  +1.2 MiB
+  glibc 2.38-27 → 2.38-44 (upgrade)
+  linux 6.6.1, 6.6.2 → 6.6.3 (upgrade)  +12.0 KiB
+  openssl 3.0.12 → 3.0.11 (downgrade)
+  zsh-theme unstable-2024-01-02 → unstable-2023-12-24 (downgrade)
+  source ε → 20240101 (upgrade)
+Size changed (1):
+  nixos-system-host  +1.5 KiB
+Net size change: +1.2 MiB"
+    );
+
+    assert!(Explanation::parse("").is_empty());
+    assert_eq!(Explanation::parse("").to_text(), "No changes");
+}
+
+#[test]
+fn test_compare_versions() {
+    use Ordering::*;
+
+    for (a, b, order) in [
+        ("1.0", "1.0", Equal),
+        ("1.2", "1.10", Less),
+        ("2.38-27", "2.38-44", Less),
+        ("1.0", "1.0.1", Less),
+        ("1.0pre", "1.0", Less),
+        ("1.0-rc1", "1.0.1", Less),
+        ("unstable-2024-01-02", "unstable-2023-12-24", Greater),
+        ("a", "1", Less),
+        ("", "1", Less),
+    ] {
+        assert_eq!(compare_versions(a, b), order, "{a} vs {b}");
+        assert_eq!(compare_versions(b, a), order.reverse(), "{b} vs {a}");
+    }
+}
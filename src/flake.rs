@@ -0,0 +1,329 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, ContextCompat};
+use color_eyre::Result;
+use nix::unistd::{access, AccessFlags};
+use tracing::{debug, instrument};
+
+use crate::commands;
+use crate::interface::{FlakeKind, FlakeRef};
+use crate::util;
+
+type Evaluator = Box<dyn Fn(&[String]) -> Result<String>>;
+
+/// Memoizes evaluations of a flake during a single nh invocation
+///
+/// Every evaluation spawns nix and instantiates the flake again, so the results are kept
+/// around. Queries about the attributes of a set are answered from a single listing of its
+/// names, so trying several names, like nh home falling back from `user@host` to `user`, costs
+/// one evaluation.
+///
+/// Only evaluations are cached. Builds still spawn nix once each, as nh builds a single host and
+/// the builds of `--for` each need their own `--system`.
+pub struct FlakeCache {
+    evaluator: Evaluator,
+    attr_names: RefCell<HashMap<String, Vec<String>>>,
+}
+
+impl std::fmt::Debug for FlakeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlakeCache")
+            .field("attr_names", &self.attr_names)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for FlakeCache {
+    fn default() -> Self {
+        Self::with_evaluator(|args| {
+            commands::CommandBuilder::default()
+                .args(args)
+                .build()?
+                .exec_capture()?
+                .wrap_err("Capturing nix output")
+        })
+    }
+}
+
+impl FlakeCache {
+    /// Uses `evaluator` to run the nix commands, which receives the full argument list
+    pub fn with_evaluator<F>(evaluator: F) -> Self
+    where
+        F: Fn(&[String]) -> Result<String> + 'static,
+    {
+        Self {
+            evaluator: Box::new(evaluator),
+            attr_names: Default::default(),
+        }
+    }
+
+    /// Names in the attribute set `set` of the flake, like the hosts of `nixosConfigurations`
+    ///
+    /// Listing the names doesn't evaluate the configurations themselves.
+    pub fn attr_names(&self, flakeref: &FlakeRef, set: &str) -> Result<Vec<String>> {
+        let output = format!("{}#{}", flakeref.as_str(), set);
+
+        if let Some(names) = self.attr_names.borrow().get(&output) {
+            return Ok(names.clone());
+        }
+
+        let result = (self.evaluator)(&[
+            util::nix_bin().into(),
+            "eval".into(),
+            "--json".into(),
+            output.clone(),
+            "--apply".into(),
+            "builtins.attrNames".into(),
+        ])?;
+
+        debug!(?result);
+
+        let names: Vec<String> = match serde_json::from_str(result.trim()) {
+            Ok(names) => names,
+            Err(_) => bail!("Failed to parse nix-eval output: {}", result),
+        };
+
+        self.attr_names.borrow_mut().insert(output, names.clone());
+        Ok(names)
+    }
+
+    /// Whether `attr` exists in the attribute set `set` of the flake, like `homeConfigurations`
+    #[instrument(ret, err, level = "debug", skip(self))]
+    pub fn has_attr(&self, flakeref: &FlakeRef, set: &str, attr: &str) -> Result<bool> {
+        Ok(self
+            .attr_names(flakeref, set)?
+            .iter()
+            .any(|name| name == attr))
+    }
+}
+
+/// Directory of a flake on the local filesystem, for path-like flakerefs
+pub fn local_dir(flakeref: &FlakeRef) -> Option<PathBuf> {
+    match flakeref.kind() {
+        FlakeKind::Local(dir) => Some(dir),
+        FlakeKind::Registry(_) | FlakeKind::Remote => None,
+    }
+}
+
+/// Parses a flakeref of the command line that nh appends an attribute to
+pub fn parse_override(flakeref: &str) -> Result<FlakeRef, String> {
+    if flakeref.is_empty() || flakeref.contains(char::is_whitespace) {
+        return Err(format!("{flakeref:?} isn't a valid flakeref"));
+    }
+    if flakeref.contains('#') {
+        return Err(format!(
+            "{flakeref:?} names an attribute, but the configuration is picked from the hostname"
+        ));
+    }
+    Ok(FlakeRef::from(flakeref))
+}
+
+/// Whether the current user can write to `dir`, so that nix can update its flake.lock
+pub fn is_writable(dir: &Path) -> bool {
+    access(dir, AccessFlags::W_OK).is_ok()
+}
+
+/// Tracked files with uncommitted changes in the git work tree containing `dir`
+///
+/// Returns `None` if `dir` isn't in a git work tree, or git isn't installed.
+pub fn dirty_files(dir: &Path) -> Result<Option<Vec<String>>> {
+    let git = |args: &[&str]| {
+        let mut cmd = commands::CommandBuilder::default();
+        cmd.args([OsStr::new("git"), OsStr::new("-C"), dir.as_os_str()])
+            .args(args);
+        cmd
+    };
+
+    // Merging stderr keeps git from complaining about a missing repository on the terminal
+    let inside = git(&["rev-parse", "--is-inside-work-tree"])
+        .merge_stderr(true)
+        .build()?
+        .exec_capture();
+    match inside {
+        Ok(Some(output)) if output.trim() == "true" => (),
+        _ => return Ok(None),
+    }
+
+    let status = git(&["status", "--porcelain", "--untracked-files=no"])
+        .build()?
+        .exec_capture()?
+        .unwrap_or_default();
+    Ok(Some(parse_dirty(&status)))
+}
+
+/// Paths in the output of `git status --porcelain`, like `M  flake.nix`
+fn parse_dirty(status: &str) -> Vec<String> {
+    status
+        .lines()
+        .filter_map(|line| line.get(3..))
+        .filter(|path| !path.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Outputs of a flake that are nested under a system, like `packages.<system>.<name>`
+const PER_SYSTEM_OUTPUTS: &[&str] = &[
+    "apps",
+    "checks",
+    "devShells",
+    "formatter",
+    "legacyPackages",
+    "packages",
+];
+
+/// Points an installable like `.#packages.x86_64-linux.hello` to the output of `system`
+///
+/// Outputs that aren't nested under a system, like `nixosConfigurations`, are left untouched.
+pub fn resolve_system_attr(installable: &str, system: &str) -> String {
+    let Some((flake, attr)) = installable.split_once('#') else {
+        return installable.to_string();
+    };
+
+    let mut pieces: Vec<&str> = attr.split('.').collect();
+    if pieces.len() >= 2 && PER_SYSTEM_OUTPUTS.contains(&pieces[0]) && pieces[1].contains('-') {
+        pieces[1] = system;
+    }
+
+    format!("{}#{}", flake, pieces.join("."))
+}
+
+#[test]
+fn test_local_dir() {
+    let dir = |s: &str| local_dir(&FlakeRef::from(s));
+
+    assert_eq!(dir("/etc/nixos#host"), Some(PathBuf::from("/etc/nixos")));
+    assert_eq!(dir("./"), Some(PathBuf::from("./")));
+    assert_eq!(
+        dir("path:/etc/nixos?dir=sub"),
+        Some(PathBuf::from("/etc/nixos"))
+    );
+    assert_eq!(dir("github:viperML/nh"), None);
+}
+
+#[test]
+fn test_dirty_files() {
+    let repo = tempfile::tempdir().unwrap();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(repo.path())
+            .args(["-c", "user.name=nh", "-c", "user.email=nh@example.org"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    };
+
+    // Not a repository yet
+    assert_eq!(dirty_files(repo.path()).unwrap(), None);
+
+    git(&["init", "--quiet"]);
+    std::fs::create_dir(repo.path().join("hosts")).unwrap();
+    std::fs::write(repo.path().join("flake.nix"), "{ }").unwrap();
+    std::fs::write(repo.path().join("hosts/laptop.nix"), "{ }").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "--quiet", "-m", "init"]);
+    assert_eq!(dirty_files(repo.path()).unwrap(), Some(vec![]));
+
+    // Untracked files aren't part of the flake, so they don't make it dirty
+    std::fs::write(repo.path().join("notes.txt"), "todo").unwrap();
+    assert_eq!(dirty_files(repo.path()).unwrap(), Some(vec![]));
+
+    std::fs::write(repo.path().join("hosts/laptop.nix"), "{ x = 1; }").unwrap();
+    std::fs::write(repo.path().join("flake.lock"), "{ }").unwrap();
+    git(&["add", "flake.lock"]);
+    assert_eq!(
+        dirty_files(&repo.path().join("hosts")).unwrap(),
+        Some(vec![
+            String::from("flake.lock"),
+            String::from("hosts/laptop.nix")
+        ])
+    );
+
+    assert_eq!(
+        parse_dirty(" M flake.nix\nR  old.nix -> new.nix\n"),
+        ["flake.nix", "old.nix -> new.nix"]
+    );
+    assert!(parse_dirty("").is_empty());
+}
+
+#[test]
+fn test_is_writable() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let writable = tempfile::tempdir().unwrap();
+    assert!(is_writable(writable.path()));
+
+    assert!(!is_writable(&writable.path().join("missing")));
+
+    let read_only = tempfile::tempdir().unwrap();
+    std::fs::set_permissions(read_only.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+    // root can write anywhere, unless the filesystem itself is read-only
+    if !nix::unistd::Uid::effective().is_root() {
+        assert!(!is_writable(read_only.path()));
+    }
+    std::fs::set_permissions(read_only.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn test_resolve_system_attr() {
+    assert_eq!(
+        resolve_system_attr(".#packages.x86_64-linux.hello", "aarch64-linux"),
+        ".#packages.aarch64-linux.hello"
+    );
+    assert_eq!(
+        resolve_system_attr(".#checks.x86_64-linux", "aarch64-linux"),
+        ".#checks.aarch64-linux"
+    );
+    assert_eq!(
+        resolve_system_attr(
+            r#"/etc/nixos#nixosConfigurations."host".config.system.build.toplevel"#,
+            "aarch64-linux"
+        ),
+        r#"/etc/nixos#nixosConfigurations."host".config.system.build.toplevel"#
+    );
+    assert_eq!(resolve_system_attr("nixpkgs", "aarch64-linux"), "nixpkgs");
+}
+
+#[test]
+fn test_flake_cache_evaluates_once() {
+    use std::{cell::Cell, rc::Rc};
+
+    let calls = Rc::new(Cell::new(0));
+    let cache = {
+        let calls = calls.clone();
+        FlakeCache::with_evaluator(move |args| {
+            calls.set(calls.get() + 1);
+            assert_eq!(args[1], "eval");
+            Ok(String::from(r#"["myhost","otherhost"]"#))
+        })
+    };
+    let flakeref = FlakeRef::from("/etc/nixos");
+
+    for _ in 0..3 {
+        assert!(cache
+            .has_attr(&flakeref, "nixosConfigurations", "myhost")
+            .unwrap());
+    }
+    assert_eq!(calls.get(), 1);
+
+    // Other names of the same set come from the same listing
+    assert!(cache
+        .has_attr(&flakeref, "nixosConfigurations", "otherhost")
+        .unwrap());
+    assert!(!cache
+        .has_attr(&flakeref, "nixosConfigurations", "missing")
+        .unwrap());
+    assert_eq!(calls.get(), 1);
+
+    cache
+        .has_attr(&flakeref, "homeConfigurations", "user")
+        .unwrap();
+    assert_eq!(calls.get(), 2);
+}
@@ -0,0 +1,278 @@
+//! Read-only check of the inputs locked in flake.lock against their upstream
+
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use color_eyre::Result;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::commands;
+use crate::interface::{FlakeArgs, FlakeStatusArgs, FlakeSubcommand, NHRunnable};
+use crate::util;
+
+impl NHRunnable for FlakeArgs {
+    fn run(&self) -> Result<()> {
+        match &self.subcommand {
+            FlakeSubcommand::Status(args) => args.status(),
+        }
+    }
+}
+
+/// How the locked revision of an input compares to its upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputStatus {
+    UpToDate,
+    Stale {
+        locked: String,
+        latest: String,
+    },
+    /// The input names a fixed revision, so there is nothing newer to follow
+    Pinned,
+    /// The input isn't tracked by revision, or its upstream couldn't be resolved
+    Unknown,
+}
+
+/// Compares the locked revision of an input to the latest one of its branch
+pub fn input_status(locked: Option<&str>, latest: Option<&str>, pinned: bool) -> InputStatus {
+    match (pinned, locked, latest) {
+        (true, _, _) => InputStatus::Pinned,
+        (false, Some(locked), Some(latest)) if locked == latest => InputStatus::UpToDate,
+        (false, Some(locked), Some(latest)) => InputStatus::Stale {
+            locked: locked.to_string(),
+            latest: latest.to_string(),
+        },
+        _ => InputStatus::Unknown,
+    }
+}
+
+/// A direct input of the flake, as recorded in flake.lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LockedInput {
+    name: String,
+    rev: Option<String>,
+    /// Flakeref of the branch the input follows, without the locked revision
+    source: Option<String>,
+    pinned: bool,
+}
+
+/// Direct inputs of the flake, from the output of `nix flake metadata --json`
+///
+/// Inputs that follow another one are skipped, as they are checked under their own name.
+fn locked_inputs(metadata: &Value) -> Result<Vec<LockedInput>> {
+    let locks = metadata
+        .get("locks")
+        .context("nix flake metadata didn't report the lock file")?;
+    let nodes = locks.get("nodes").context("flake.lock has no nodes")?;
+    let root = locks.get("root").and_then(Value::as_str).unwrap_or("root");
+
+    let Some(inputs) = nodes
+        .get(root)
+        .and_then(|root| root.get("inputs"))
+        .and_then(Value::as_object)
+    else {
+        return Ok(Vec::new());
+    };
+
+    Ok(inputs
+        .iter()
+        .filter_map(|(name, node)| {
+            let node = nodes.get(node.as_str()?)?;
+            let original = node.get("original");
+            Some(LockedInput {
+                name: name.clone(),
+                rev: node
+                    .pointer("/locked/rev")
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                source: original.and_then(source_url),
+                pinned: original.is_some_and(|original| original.get("rev").is_some()),
+            })
+        })
+        .collect())
+}
+
+/// Flakeref of an `original` entry of flake.lock, for the kinds of inputs that follow a branch
+fn source_url(original: &Value) -> Option<String> {
+    let attr = |name| original.get(name).and_then(Value::as_str);
+    let kind = attr("type")?;
+
+    let mut url = match kind {
+        "github" | "gitlab" | "sourcehut" => {
+            let mut url = format!("{kind}:{}/{}", attr("owner")?, attr("repo")?);
+            if let Some(git_ref) = attr("ref") {
+                url.push('/');
+                url.push_str(git_ref);
+            }
+            return Some(url);
+        }
+        "git" => {
+            let url = attr("url")?;
+            if url.starts_with("git+") {
+                url.to_string()
+            } else {
+                format!("git+{url}")
+            }
+        }
+        "indirect" => {
+            let mut url = format!("flake:{}", attr("id")?);
+            if let Some(git_ref) = attr("ref") {
+                url.push('/');
+                url.push_str(git_ref);
+            }
+            return Some(url);
+        }
+        _ => return None,
+    };
+
+    if let Some(git_ref) = attr("ref") {
+        url.push_str(if url.contains('?') { "&ref=" } else { "?ref=" });
+        url.push_str(git_ref);
+    }
+    Some(url)
+}
+
+/// Latest revision of `source`, bypassing the cache of nix
+fn latest_rev(source: &str) -> Result<Option<String>> {
+    let output = commands::CommandBuilder::default()
+        .args([
+            util::nix_bin(),
+            "flake",
+            "metadata",
+            "--json",
+            "--refresh",
+            source,
+        ])
+        .build()?
+        .exec_capture()?
+        .unwrap_or_default();
+    let metadata: Value = serde_json::from_str(&output)
+        .wrap_err_with(|| format!("Parsing the metadata of {source}"))?;
+
+    Ok(metadata
+        .get("revision")
+        .or_else(|| metadata.pointer("/locked/rev"))
+        .and_then(Value::as_str)
+        .map(String::from))
+}
+
+impl FlakeStatusArgs {
+    fn status(&self) -> Result<()> {
+        let output = commands::CommandBuilder::default()
+            .args([
+                util::nix_bin(),
+                "flake",
+                "metadata",
+                "--json",
+                "--no-write-lock-file",
+                &self.flakeref,
+            ])
+            .message("Reading flake.lock")
+            .build()?
+            .exec_capture()?
+            .unwrap_or_default();
+        let metadata: Value =
+            serde_json::from_str(&output).wrap_err("Parsing nix flake metadata output")?;
+
+        let mut stale = 0;
+        for input in locked_inputs(&metadata)? {
+            let latest = match (&input.source, input.pinned) {
+                (Some(source), false) => latest_rev(source).unwrap_or_else(|err| {
+                    warn!("Couldn't resolve {source}: {err}");
+                    None
+                }),
+                _ => None,
+            };
+
+            match input_status(input.rev.as_deref(), latest.as_deref(), input.pinned) {
+                InputStatus::UpToDate => info!("{}: up to date", input.name),
+                InputStatus::Stale { locked, latest } => {
+                    stale += 1;
+                    warn!("{}: {locked} is behind {latest}", input.name);
+                }
+                InputStatus::Pinned => info!("{}: pinned", input.name),
+                InputStatus::Unknown => info!("{}: unknown", input.name),
+            }
+        }
+
+        if stale > 0 {
+            bail!("{stale} input(s) are out of date");
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_input_status() {
+    assert_eq!(
+        input_status(Some("abc"), Some("abc"), false),
+        InputStatus::UpToDate
+    );
+    assert_eq!(
+        input_status(Some("abc"), Some("def"), false),
+        InputStatus::Stale {
+            locked: String::from("abc"),
+            latest: String::from("def"),
+        }
+    );
+    assert_eq!(
+        input_status(Some("abc"), Some("def"), true),
+        InputStatus::Pinned
+    );
+    assert_eq!(input_status(None, Some("def"), false), InputStatus::Unknown);
+    assert_eq!(input_status(Some("abc"), None, false), InputStatus::Unknown);
+}
+
+#[test]
+fn test_locked_inputs() {
+    let metadata: Value = serde_json::from_str(
+        r#"{
+            "locks": {
+                "nodes": {
+                    "home-manager": {
+                        "inputs": { "nixpkgs": ["nixpkgs"] },
+                        "locked": { "owner": "nix-community", "repo": "home-manager", "rev": "111", "type": "github" },
+                        "original": { "owner": "nix-community", "repo": "home-manager", "type": "github" }
+                    },
+                    "nixpkgs": {
+                        "locked": { "owner": "NixOS", "repo": "nixpkgs", "rev": "222", "type": "github" },
+                        "original": { "owner": "NixOS", "ref": "nixos-unstable", "repo": "nixpkgs", "type": "github" }
+                    },
+                    "secrets": {
+                        "locked": { "rev": "333", "type": "git", "url": "ssh://git@example.org/secrets" },
+                        "original": { "ref": "main", "rev": "333", "type": "git", "url": "ssh://git@example.org/secrets" }
+                    },
+                    "root": {
+                        "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs", "secrets": "secrets", "shared": ["home-manager", "nixpkgs"] }
+                    }
+                },
+                "root": "root",
+                "version": 7
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        locked_inputs(&metadata).unwrap(),
+        [
+            LockedInput {
+                name: String::from("home-manager"),
+                rev: Some(String::from("111")),
+                source: Some(String::from("github:nix-community/home-manager")),
+                pinned: false,
+            },
+            LockedInput {
+                name: String::from("nixpkgs"),
+                rev: Some(String::from("222")),
+                source: Some(String::from("github:NixOS/nixpkgs/nixos-unstable")),
+                pinned: false,
+            },
+            LockedInput {
+                name: String::from("secrets"),
+                rev: Some(String::from("333")),
+                source: Some(String::from("git+ssh://git@example.org/secrets?ref=main")),
+                pinned: true,
+            },
+        ]
+    );
+}
@@ -0,0 +1,162 @@
+//! Generations of nix profiles
+//!
+//! A profile is a symlink to `<profile>-<number>-link`, which points to the store path of the
+//! generation.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::util;
+
+static LINK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"-(\d+)-link$").unwrap());
+
+/// Number of the generation the profile currently points to
+pub fn current(profile: &Path) -> Option<u32> {
+    let link = profile.read_link().ok()?;
+    number(&link)
+}
+
+/// Number of a generation from its `<profile>-<number>-link` path
+pub fn number(link: &Path) -> Option<u32> {
+    let name = link.file_name()?.to_str()?;
+    LINK_REGEX.captures(name)?[1].parse().ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationInfo {
+    pub number: u32,
+    /// Store path the generation points to
+    pub path: PathBuf,
+    pub last_modified: SystemTime,
+    /// Whether the profile currently points to this generation
+    pub current: bool,
+}
+
+/// Every generation of a profile, sorted by number
+pub fn list(profile: &Path) -> Result<Vec<GenerationInfo>> {
+    let name = profile
+        .file_name()
+        .context("Checking profile's name")?
+        .to_str()
+        .unwrap();
+    let current = current(profile);
+
+    let mut result = Vec::new();
+    for entry in profile
+        .parent()
+        .context("Reading profile's parent dir")?
+        .read_dir()
+        .context("Reading profile's generations")?
+    {
+        let link = entry?.path();
+        let file_name = link.file_name().unwrap().to_string_lossy();
+        let Some(number) = file_name
+            .strip_prefix(name)
+            .filter(|rest| rest.starts_with('-'))
+            .and_then(|_| self::number(&link))
+        else {
+            continue;
+        };
+
+        result.push(GenerationInfo {
+            number,
+            path: link
+                .read_link()
+                .context("Reading generation's store path")?,
+            last_modified: link
+                .symlink_metadata()
+                .context("Checking symlink metadata")?
+                .modified()
+                .context("Reading modified time")?,
+            current: Some(number) == current,
+        });
+    }
+
+    result.sort_by_key(|g| g.number);
+    Ok(result)
+}
+
+/// Closure sizes from `nix path-info --closure-size --json`, by store path
+///
+/// Paths that aren't valid in the store are left out.
+pub fn parse_closure_sizes(json: &str) -> Result<BTreeMap<PathBuf, u64>> {
+    Ok(util::path_info_entries(json)?
+        .into_iter()
+        .filter_map(|(path, info)| Some((path, info.get("closureSize")?.as_u64()?)))
+        .collect())
+}
+
+#[test]
+fn test_parse_closure_sizes() {
+    let expected = BTreeMap::from([
+        (PathBuf::from("/nix/store/aaa-nixos-system"), 1000),
+        (PathBuf::from("/nix/store/bbb-nixos-system"), 2500),
+    ]);
+
+    let current = r#"{
+        "/nix/store/aaa-nixos-system": {"closureSize": 1000, "narSize": 10, "references": []},
+        "/nix/store/bbb-nixos-system": {"closureSize": 2500, "narSize": 20, "references": []},
+        "/nix/store/ccc-nixos-system": null
+    }"#;
+    assert_eq!(parse_closure_sizes(current).unwrap(), expected);
+
+    let legacy = r#"[
+        {"path": "/nix/store/aaa-nixos-system", "closureSize": 1000, "narSize": 10},
+        {"path": "/nix/store/bbb-nixos-system", "closureSize": 2500, "narSize": 20},
+        {"path": "/nix/store/ccc-nixos-system", "valid": false}
+    ]"#;
+    assert_eq!(parse_closure_sizes(legacy).unwrap(), expected);
+
+    assert!(parse_closure_sizes("42").is_err());
+    assert!(parse_closure_sizes("not json").is_err());
+}
+
+#[test]
+fn test_list_generations() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    symlink(
+        "/nix/store/aaa-nixos-system",
+        dir.path().join("system-1-link"),
+    )
+    .unwrap();
+    symlink(
+        "/nix/store/bbb-nixos-system",
+        dir.path().join("system-2-link"),
+    )
+    .unwrap();
+    symlink("/nix/store/ccc-other", dir.path().join("other-3-link")).unwrap();
+    symlink("system-2-link", dir.path().join("system")).unwrap();
+
+    let generations = list(&dir.path().join("system")).unwrap();
+    let summary: Vec<_> = generations
+        .iter()
+        .map(|g| (g.number, g.path.to_str().unwrap(), g.current))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (1, "/nix/store/aaa-nixos-system", false),
+            (2, "/nix/store/bbb-nixos-system", true),
+        ]
+    );
+}
+
+#[test]
+fn test_generation_number() {
+    assert_eq!(number(Path::new("system-42-link")), Some(42));
+    assert_eq!(
+        number(Path::new("/nix/var/nix/profiles/system-1-link")),
+        Some(1)
+    );
+    assert_eq!(number(Path::new("/nix/var/nix/profiles/system")), None);
+}
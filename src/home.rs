@@ -5,13 +5,16 @@ use std::path::PathBuf;
 use color_eyre::eyre::bail;
 use color_eyre::Result;
 use thiserror::Error;
-use tracing::{debug, warn, info, instrument};
+use tracing::{debug, info, warn};
 
 use crate::*;
 use crate::{
+    config::Config,
+    flake::FlakeCache,
+    hooks::Phase,
     interface::NHRunnable,
-    interface::{FlakeRef, HomeArgs, HomeRebuildArgs, HomeSubcommand, HomeEditArgs},
-    util::{compare_semver, get_nix_version},
+    interface::{FlakeRef, HomeArgs, HomeEditArgs, HomeRebuildArgs, HomeSubcommand},
+    util,
 };
 
 #[derive(Error, Debug)]
@@ -27,9 +30,7 @@ impl NHRunnable for HomeArgs {
             HomeSubcommand::Switch(args) | HomeSubcommand::Build(args) => {
                 args.rebuild(&self.subcommand)
             }
-            HomeSubcommand::Edit(args) => {
-                args.edit()
-            }
+            HomeSubcommand::Edit(args) => args.edit(),
             s => bail!("Subcommand {:?} not yet implemented", s),
         }
     }
@@ -37,12 +38,20 @@ impl NHRunnable for HomeArgs {
 
 impl HomeEditArgs {
     fn edit(&self) -> Result<()> {
-        commands::edit(self.flakeref.clone())
+        commands::edit(self.flakeref.clone(), self.dry)
     }
 }
 
 impl HomeRebuildArgs {
     fn rebuild(&self, action: &HomeSubcommand) -> Result<()> {
+        if self.common.store.is_some() && !matches!(action, HomeSubcommand::Build(_)) {
+            bail!("--store can only be used with nh home build, as the result can't be activated");
+        }
+
+        if self.attr.is_some() && !matches!(action, HomeSubcommand::Build(_)) {
+            bail!("--attr can only be used with nh home build");
+        }
+
         let out_dir = tempfile::Builder::new().prefix("nh-home-").tempdir()?;
         let out_link = out_dir.path().join("result");
         let out_link_str = out_link.to_str().unwrap();
@@ -51,47 +60,58 @@ impl HomeRebuildArgs {
 
         let username = std::env::var("USER").expect("Couldn't get username");
 
-        let flakeref = self.flakeref.clone().or_else(|| {
-            warn!("NH_HOME_FLAKE not set");
-            std::env::var("FLAKE").ok().map(|s| FlakeRef(s))
-        }).unwrap_or("./".into());
+        let flakeref = self
+            .flakeref
+            .clone()
+            .or_else(|| {
+                warn!("NH_HOME_FLAKE not set");
+                std::env::var("FLAKE").ok().map(FlakeRef)
+            })
+            .unwrap_or("./".into());
+
+        let config = Config::load(&flakeref)?;
+        debug!(?config);
+
+        self.common.check_clean(&flakeref)?;
+
+        let phases = self.common.phases();
+        debug!(?phases);
+
+        let flake_cache = FlakeCache::default();
 
         let hm_config_name = match &self.configuration {
+            Some(name) if !phases.preflight => name.to_owned(),
             Some(name) => {
-                if configuration_exists(&flakeref, name)? {
+                if configuration_exists(&flake_cache, &flakeref, name)? {
                     name.to_owned()
                 } else {
                     return Err(HomeRebuildError::ConfigName(name.to_owned()).into());
                 }
             }
-            None => get_home_output(&flakeref, &username)?,
+            None => get_home_output(&flake_cache, &flakeref, &username)?,
         };
 
         debug!("hm_config_name: {}", hm_config_name);
-        
-        let flakeref = format!(
-            "{}#homeConfigurations.\"{}\".config.home.activationPackage",
-            flakeref.deref(),
-            hm_config_name
-        );
+
+        let read_only_lock_file = self.common.read_only_lock_file(&flakeref);
+
+        let flakeref = home_attr_path(&flakeref, &hm_config_name, self.attr.as_deref());
 
         if self.common.update {
-            // Get the Nix version
-            let nix_version = get_nix_version().unwrap_or_else(|_| {
+            let nix_version = util::nix_version().unwrap_or_else(|_| {
                 panic!("Failed to get Nix version. Custom Nix fork?");
             });
 
             // Default interface for updating flake inputs
-            let mut update_args = vec!["nix", "flake", "update"];
+            let mut update_args = vec![util::nix_bin(), "flake", "update"];
 
-            // If user is on Nix 2.19.0 or above, --flake must be passed
-            if let Ok(ordering) = compare_semver(&nix_version, "2.19.0") {
-                if ordering == std::cmp::Ordering::Greater {
-                    update_args.push("--flake");
-                }
+            // Nix 2.19.0 and above expect the flake to be passed with --flake
+            if nix_version.flake_update_needs_flag() {
+                update_args.push("--flake");
             }
 
             update_args.push(&flakeref);
+            update_args.extend(self.common.nix_flags().iter().map(String::as_str));
 
             debug!("nix_version: {:?}", nix_version);
             debug!("update_args: {:?}", update_args);
@@ -103,15 +123,65 @@ impl HomeRebuildArgs {
                 .exec()?;
         }
 
-        commands::BuildCommandBuilder::default()
+        let link_args = if self.common.builds_nothing() {
+            vec![]
+        } else if phases.link {
+            vec!["--out-link", out_link_str]
+        } else {
+            vec!["--no-link"]
+        };
+
+        self.common.hooks.run(Phase::PreBuild, None)?;
+
+        let out_paths = commands::BuildCommandBuilder::default()
             .flakeref(&flakeref)
-            .extra_args(["--out-link", out_link_str])
+            .extra_args(link_args)
+            .extra_args(config.extra_args())
+            .extra_args(self.common.nix_flags())
             .extra_args(&self.extra_args)
             .message("Building home configuration")
-            .nom(!self.common.no_nom)
+            .nom(self.common.use_nom(&config)?)
+            .print_build_logs(self.common.print_build_logs)
+            .builders_use_substitutes(self.common.builders_use_substitutes)
+            .store(self.common.store.clone())
+            .system(self.common.system.clone())
+            .build_report(self.common.build_report)
+            .trace_file(self.common.trace_file.clone())
+            .auto_features(self.common.auto_features)
+            .auto_recover(self.common.auto_recover)
+            .warning_summary(!self.common.no_warning_summary)
+            .eval_only(self.common.eval_only)
+            .report_hashes(self.common.report_hashes)
+            .progress_json(self.common.progress_json)
+            .stderr_policy(self.common.nix_stderr)
+            .plan(self.common.plan || self.common.plan_paths)
+            .plan_paths(self.common.plan_paths)
+            .recreate_lock_file(self.common.recreate_lock_file)
+            .refresh(self.common.refresh)
+            .read_only_lock_file(read_only_lock_file)
             .build()?
             .exec()?;
 
+        // The derivation or the plan was already printed, there is nothing to activate
+        if self.common.builds_nothing() {
+            return Ok(());
+        }
+
+        self.common
+            .hooks
+            .run(Phase::PostBuild, out_paths.first().map(PathBuf::as_path))?;
+
+        let built = match (phases.link, out_paths.first()) {
+            (true, _) => out_link.clone(),
+            (false, Some(path)) => path.clone(),
+            (false, None) => bail!("nix build didn't report any output path"),
+        };
+        let built_str = built.to_str().unwrap();
+
+        if self.attr.is_some() {
+            return commands::print_out_paths(std::io::stdout(), &out_paths);
+        }
+
         let prev_generation: Option<PathBuf> = [
             PathBuf::from("/nix/var/nix/profiles/per-user")
                 .join(username)
@@ -126,12 +196,11 @@ impl HomeRebuildArgs {
         debug!("prev_generation: {:?}", prev_generation);
 
         // just do nothing for None case (fresh installs)
-        if let Some(prev_gen) = prev_generation {
-            commands::CommandBuilder::default()
-                .args(self.common.diff_provider.split_ascii_whitespace())
-                .args([(prev_gen.to_str().unwrap()), out_link_str])
-                .message("Comparing changes")
-                .build()?
+        if let (true, Some(prev_gen)) = (phases.diff, prev_generation) {
+            self.common
+                .diff_tool
+                .resolve()
+                .command(&prev_gen, built_str)?
                 .exec()?;
         }
 
@@ -139,7 +208,7 @@ impl HomeRebuildArgs {
             return Ok(());
         }
 
-        if self.common.ask {
+        if self.common.ask && !self.common.yes {
             info!("Apply the config?");
             let confirmation = dialoguer::Confirm::new().default(false).interact()?;
 
@@ -153,20 +222,26 @@ impl HomeRebuildArgs {
             env::set_var("HOME_MANAGER_BACKUP_EXT", ext);
         }
 
+        let store_path = out_paths.first().map(PathBuf::as_path);
+        self.common.hooks.run(Phase::PreActivate, store_path)?;
+
         commands::CommandBuilder::default()
-            .args([&format!("{}/activate", out_link_str)])
+            .args([&format!("{}/activate", built_str)])
             .message("Activating configuration")
             .build()?
             .exec()?;
 
+        self.common.hooks.run(Phase::PostActivate, store_path)?;
+
         // Drop the out dir *only* when we are finished
         drop(out_dir);
 
         Ok(())
-    }   
+    }
 }
 
 fn get_home_output<S: AsRef<str> + std::fmt::Display>(
+    flake_cache: &FlakeCache,
     flakeref: &FlakeRef,
     username: S,
 ) -> Result<String> {
@@ -180,9 +255,9 @@ fn get_home_output<S: AsRef<str> + std::fmt::Display>(
 
     let username_hostname = format!("{}@{}", username, &hostname);
 
-    if configuration_exists(flakeref, &username_hostname)? {
+    if configuration_exists(flake_cache, flakeref, &username_hostname)? {
         Ok(username_hostname)
-    } else if configuration_exists(flakeref, username.as_ref())? {
+    } else if configuration_exists(flake_cache, flakeref, username.as_ref())? {
         Ok(username.to_string())
     } else {
         bail!(
@@ -192,22 +267,68 @@ fn get_home_output<S: AsRef<str> + std::fmt::Display>(
     }
 }
 
-#[instrument(ret, err, level = "debug")]
-fn configuration_exists(flakeref: &FlakeRef, configuration: &str) -> Result<bool> {
-    let output = format!("{}#homeConfigurations", flakeref.deref());
-    let filter = format!(r#" x: x ? "{}" "#, configuration);
+/// Installable of the configuration `name`, its activation package unless `attr` is given
+fn home_attr_path(flakeref: &FlakeRef, name: &str, attr: Option<&str>) -> String {
+    let attr = attr
+        .map(|attr| attr.trim_start_matches('.'))
+        .unwrap_or("config.home.activationPackage");
+
+    format!(
+        "{}#homeConfigurations.\"{}\".{}",
+        flakeref.deref(),
+        name,
+        attr
+    )
+}
 
-    let result = commands::CommandBuilder::default()
-        .args(["nix", "eval", &output, "--apply", &filter])
-        .build()?
-        .exec_capture()?
-        .unwrap();
+fn configuration_exists(
+    flake_cache: &FlakeCache,
+    flakeref: &FlakeRef,
+    configuration: &str,
+) -> Result<bool> {
+    flake_cache.has_attr(flakeref, "homeConfigurations", configuration)
+}
 
-    debug!(?result);
+#[test]
+fn test_home_attr_path() {
+    let flakeref = FlakeRef::from("/home/user/flake");
+
+    assert_eq!(
+        home_attr_path(&flakeref, "user@host", None),
+        r#"/home/user/flake#homeConfigurations."user@host".config.home.activationPackage"#
+    );
+    assert_eq!(
+        home_attr_path(&flakeref, "user@host", Some("config.home.path")),
+        r#"/home/user/flake#homeConfigurations."user@host".config.home.path"#
+    );
+    assert_eq!(
+        home_attr_path(&flakeref, "user", Some(".config.news.json.output")),
+        r#"/home/user/flake#homeConfigurations."user".config.news.json.output"#
+    );
+}
 
-    match result.as_str().trim() {
-        "true" => Ok(true),
-        "false" => Ok(false),
-        _ => bail!("Failed to parse nix-eval output: {}", result),
-    }
+#[test]
+fn test_home_output_evaluates_once() {
+    use std::{cell::Cell, rc::Rc};
+
+    let calls = Rc::new(Cell::new(0));
+    let cache = {
+        let calls = calls.clone();
+        FlakeCache::with_evaluator(move |args| {
+            calls.set(calls.get() + 1);
+            assert_eq!(args[1], "eval");
+            Ok(String::from(r#"["alice","bob@server"]"#))
+        })
+    };
+    let flakeref = FlakeRef::from("/home/alice/flake");
+
+    // No alice@<hostname>, so this falls back to alice, from the same listing
+    assert_eq!(
+        get_home_output(&cache, &flakeref, "alice").unwrap(),
+        "alice"
+    );
+    assert_eq!(calls.get(), 1);
+
+    assert!(get_home_output(&cache, &flakeref, "carol").is_err());
+    assert_eq!(calls.get(), 1);
 }
@@ -0,0 +1,130 @@
+//! User scripts run around the build and activation of a configuration
+
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use tracing::warn;
+
+use crate::commands::{Command, CommandBuilder};
+use crate::interface::HookArgs;
+
+/// Point of the rebuild where a hook runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    PreBuild,
+    PostBuild,
+    PreActivate,
+    PostActivate,
+}
+
+impl Phase {
+    /// Name of the phase, as passed in `NH_PHASE`
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::PreBuild => "pre_build",
+            Phase::PostBuild => "post_build",
+            Phase::PreActivate => "pre_activate",
+            Phase::PostActivate => "post_activate",
+        }
+    }
+
+    /// Whether a failure of the hook should abort the rebuild
+    fn is_pre(self) -> bool {
+        matches!(self, Phase::PreBuild | Phase::PreActivate)
+    }
+}
+
+impl HookArgs {
+    fn script(&self, phase: Phase) -> Option<&str> {
+        match phase {
+            Phase::PreBuild => self.pre_build.as_deref(),
+            Phase::PostBuild => self.post_build.as_deref(),
+            Phase::PreActivate => self.pre_activate.as_deref(),
+            Phase::PostActivate => self.post_activate.as_deref(),
+        }
+    }
+
+    /// Runs the hook of `phase`, if any
+    ///
+    /// A failing pre-hook aborts the rebuild, while a failing post-hook only warns.
+    pub fn run(&self, phase: Phase, store_path: Option<&Path>) -> Result<()> {
+        let Some(script) = self.script(phase) else {
+            return Ok(());
+        };
+
+        let result = hook_command(script, phase, store_path)?.exec();
+
+        match result {
+            Err(err) if !phase.is_pre() => {
+                warn!("The {} hook failed: {err}", phase.name());
+                Ok(())
+            }
+            result => result.wrap_err(format!("The {} hook failed, aborting", phase.name())),
+        }
+    }
+}
+
+/// Runs `script` with the shell, describing the phase in its environment
+fn hook_command(script: &str, phase: Phase, store_path: Option<&Path>) -> Result<Command> {
+    let mut cmd = CommandBuilder::default();
+    cmd.args(["sh", "-c", script])
+        .message(format!("Running the {} hook", phase.name()))
+        .env("NH_PHASE", phase.name());
+
+    if let Some(store_path) = store_path {
+        cmd.env("NH_STORE_PATH", store_path);
+    }
+
+    Ok(cmd.build()?)
+}
+
+#[test]
+fn test_hook_env() {
+    let cmd = hook_command(
+        "echo $NH_STORE_PATH",
+        Phase::PostBuild,
+        Some(Path::new("/nix/store/abc-nixos-system")),
+    )
+    .unwrap();
+
+    assert_eq!(cmd.to_args(), ["sh", "-c", "echo $NH_STORE_PATH"]);
+    assert_eq!(cmd.env_var("NH_PHASE"), Some("post_build".as_ref()));
+    assert_eq!(
+        cmd.env_var("NH_STORE_PATH"),
+        Some("/nix/store/abc-nixos-system".as_ref())
+    );
+
+    let cmd = hook_command("true", Phase::PreBuild, None).unwrap();
+    assert_eq!(cmd.env_var("NH_PHASE"), Some("pre_build".as_ref()));
+    assert_eq!(cmd.env_var("NH_STORE_PATH"), None);
+
+    // The variables reach the script
+    let hooks = HookArgs {
+        pre_activate: Some(String::from(
+            r#"test "$NH_PHASE" = pre_activate && test "$NH_STORE_PATH" = /nix/store/abc"#,
+        )),
+        ..Default::default()
+    };
+    hooks
+        .run(Phase::PreActivate, Some(Path::new("/nix/store/abc")))
+        .unwrap();
+}
+
+#[test]
+fn test_failing_hooks() {
+    let hooks = HookArgs {
+        pre_build: Some(String::from("exit 1")),
+        post_build: Some(String::from("exit 1")),
+        pre_activate: Some(String::from("exit 1")),
+        post_activate: Some(String::from("exit 1")),
+    };
+
+    assert!(hooks.run(Phase::PreBuild, None).is_err());
+    assert!(hooks.run(Phase::PreActivate, None).is_err());
+    assert!(hooks.run(Phase::PostBuild, None).is_ok());
+    assert!(hooks.run(Phase::PostActivate, None).is_ok());
+
+    // Phases without a hook are skipped
+    assert!(HookArgs::default().run(Phase::PreBuild, None).is_ok());
+}
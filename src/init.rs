@@ -0,0 +1,178 @@
+//! Scaffolding of a minimal flake.nix for newcomers
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use tracing::info;
+
+use crate::interface::{InitArgs, InitTemplate, NHRunnable};
+
+const NIXOS_TEMPLATE: &str = r#"{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+
+  outputs = { nixpkgs, ... }: {
+    nixosConfigurations."@hostname@" = nixpkgs.lib.nixosSystem {
+      system = "@system@";
+      modules = [
+        ./configuration.nix
+      ];
+    };
+  };
+}
+"#;
+
+const HOME_TEMPLATE: &str = r#"{
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+    home-manager = {
+      url = "github:nix-community/home-manager";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs = { nixpkgs, home-manager, ... }: {
+    homeConfigurations."@username@@@hostname@" = home-manager.lib.homeManagerConfiguration {
+      pkgs = nixpkgs.legacyPackages."@system@";
+      modules = [
+        ./home.nix
+      ];
+    };
+  };
+}
+"#;
+
+const DARWIN_TEMPLATE: &str = r#"{
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixpkgs-unstable";
+    nix-darwin = {
+      url = "github:nix-darwin/nix-darwin";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs = { nix-darwin, ... }: {
+    darwinConfigurations."@hostname@" = nix-darwin.lib.darwinSystem {
+      system = "@system@";
+      modules = [
+        ./configuration.nix
+      ];
+    };
+  };
+}
+"#;
+
+/// Names that can go between quotes in nix without escaping
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("Invalid {kind} {name:?} for the flake, expected letters, digits, '-', '_' or '.'");
+    }
+    Ok(())
+}
+
+/// flake.nix of `template`, for the configuration of `hostname` and `username`
+pub fn render(template: InitTemplate, hostname: &str, username: &str) -> Result<String> {
+    check_name("hostname", hostname)?;
+    let (text, os) = match template {
+        InitTemplate::Nixos => (NIXOS_TEMPLATE, "linux"),
+        InitTemplate::Home => {
+            check_name("username", username)?;
+            (HOME_TEMPLATE, "linux")
+        }
+        InitTemplate::Darwin => (DARWIN_TEMPLATE, "darwin"),
+    };
+
+    Ok(text
+        .replace("@hostname@", hostname)
+        .replace("@username@", username)
+        .replace("@system@", &format!("{}-{os}", std::env::consts::ARCH)))
+}
+
+/// Writes `contents` to flake.nix in `dir`, refusing to overwrite an existing one
+fn write_flake(dir: &Path, contents: &str) -> Result<PathBuf> {
+    let path = dir.join("flake.nix");
+    let mut file = match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            bail!("{path:?} already exists, not overwriting it")
+        }
+        Err(err) => return Err(err).wrap_err_with(|| format!("Creating {path:?}")),
+    };
+    file.write_all(contents.as_bytes())
+        .wrap_err_with(|| format!("Writing {path:?}"))?;
+    Ok(path)
+}
+
+impl NHRunnable for InitArgs {
+    fn run(&self) -> Result<()> {
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => hostname::get()
+                .context("Failed to get hostname")?
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let username = match self.template {
+            InitTemplate::Home => {
+                std::env::var("USER").context("Couldn't get the username from $USER")?
+            }
+            _ => String::new(),
+        };
+
+        let contents = render(self.template, &hostname, &username)?;
+        let path = write_flake(&std::env::current_dir()?, &contents)?;
+        info!("Wrote {}", path.display());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_render() {
+    let system = std::env::consts::ARCH;
+
+    let nixos = render(InitTemplate::Nixos, "laptop", "alice").unwrap();
+    assert!(nixos.contains(r#"nixosConfigurations."laptop" = nixpkgs.lib.nixosSystem {"#));
+    assert!(nixos.contains(&format!(r#"system = "{system}-linux";"#)));
+    assert!(!nixos.contains('@'));
+
+    let home = render(InitTemplate::Home, "laptop", "alice").unwrap();
+    assert!(home.contains(r#"homeConfigurations."alice@laptop" = "#));
+    assert!(home.contains(&format!(
+        r#"pkgs = nixpkgs.legacyPackages."{system}-linux";"#
+    )));
+    assert!(home.contains(r#"inputs.nixpkgs.follows = "nixpkgs";"#));
+
+    let darwin = render(InitTemplate::Darwin, "macbook", "alice").unwrap();
+    assert!(darwin.contains(r#"darwinConfigurations."macbook" = nix-darwin.lib.darwinSystem {"#));
+    assert!(darwin.contains(&format!(r#"system = "{system}-darwin";"#)));
+    assert!(!darwin.contains('@'));
+
+    // Names that would need escaping in nix
+    assert!(render(InitTemplate::Nixos, "", "alice").is_err());
+    assert!(render(InitTemplate::Nixos, "my\"host", "alice").is_err());
+    assert!(render(InitTemplate::Home, "laptop", "${x}").is_err());
+    // The username only matters to home-manager
+    assert!(render(InitTemplate::Nixos, "laptop", "").is_ok());
+}
+
+#[test]
+fn test_write_flake() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_flake(dir.path(), "{ }\n").unwrap();
+    assert_eq!(path, dir.path().join("flake.nix"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ }\n");
+
+    // An existing flake is left alone
+    let err = write_flake(dir.path(), "{ outputs = _: { }; }\n").unwrap_err();
+    assert!(err.to_string().contains("already exists"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ }\n");
+}
@@ -4,6 +4,10 @@ use clap::{builder::Styles, Args, Parser, Subcommand};
 use color_eyre::Result;
 use std::{ffi::OsString, ops::Deref, path::PathBuf};
 
+use crate::config::Config;
+use crate::diff::DiffTool;
+use crate::util::NixFlags;
+
 #[derive(Debug, Clone, Default)]
 pub struct FlakeRef(pub String);
 impl From<&str> for FlakeRef {
@@ -25,6 +29,51 @@ impl Deref for FlakeRef {
     }
 }
 
+/// Where a flakeref points to, as far as can be told without asking nix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlakeKind {
+    /// A directory on this machine, like `.#host`, `/etc/nixos` or `path:/etc/nixos`
+    Local(PathBuf),
+    /// An alias resolved through the flake registry, like `nixpkgs#hello`
+    Registry(String),
+    /// Anything with a scheme, like `github:owner/repo`
+    Remote,
+}
+
+impl FlakeRef {
+    pub fn kind(&self) -> FlakeKind {
+        let url = self.split('#').next().unwrap();
+        let url = url.split('?').next().unwrap();
+
+        if let Some(path) = url
+            .strip_prefix("path:")
+            .or_else(|| url.strip_prefix("git+file://"))
+        {
+            return FlakeKind::Local(PathBuf::from(path));
+        }
+        if url.starts_with('/') || url.starts_with('.') {
+            return FlakeKind::Local(PathBuf::from(url));
+        }
+
+        // Registry entries are `id`, `id/ref` or `id/ref/rev`
+        let id = url
+            .strip_prefix("flake:")
+            .unwrap_or(url)
+            .split('/')
+            .next()
+            .unwrap();
+        let is_id = id.starts_with(|c: char| c.is_ascii_alphabetic())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if is_id && (url.starts_with("flake:") || !url.contains(':')) {
+            FlakeKind::Registry(id.to_string())
+        } else {
+            FlakeKind::Remote
+        }
+    }
+}
+
 fn make_style() -> Styles {
     Styles::plain().header(Style::new().bold()).literal(
         Style::new()
@@ -47,6 +96,42 @@ pub struct NHParser {
     /// Show debug logs
     pub verbose: bool,
 
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    /// Only print errors, and the nix logs if a build fails
+    pub quiet: bool,
+
+    #[arg(long, global = true)]
+    /// Print every command, quoted for the shell, before running it
+    pub show_command: bool,
+
+    #[arg(long, global = true, value_name = "TAG")]
+    /// Prefix every line of output of the commands with [TAG], to tell apart several nh running
+    /// side by side. Disables nom
+    pub output_prefix: Option<String>,
+
+    #[arg(long, global = true, env = "NH_NIX_BIN", value_name = "PATH")]
+    /// nix binary to run, like an alternate implementation installed under another name
+    pub nix_bin: Option<String>,
+
+    #[arg(long, global = true, env = "NH_NIX_VARIANT", value_enum)]
+    /// Implementation of nix, detected from nix --version by default
+    pub nix_variant: Option<crate::util::NixVariant>,
+
+    #[arg(long, global = true, env = "NH_ELEVATION_PROGRAM", value_enum)]
+    /// Program to run commands as root with. Defaults to the elevation-program of the global
+    /// config file, or sudo
+    pub elevation_program: Option<crate::elevate::ElevationProgram>,
+
+    #[arg(long, global = true, env = "NH_ASKPASS", value_name = "PATH")]
+    /// Program asking for the sudo password when there is no terminal, passed as SUDO_ASKPASS
+    /// with sudo -A. Defaults to the askpass of the global config file
+    pub askpass: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    /// Notify when the command finishes, with notify-send in a graphical session, or else by
+    /// posting to the ntfy-topic of the global config file
+    pub notify: bool,
+
     #[command(subcommand)]
     pub command: NHCommand,
 }
@@ -65,6 +150,12 @@ pub enum NHCommand {
     Search(SearchArgs),
     Clean(CleanProxy),
     Completions(CompletionArgs),
+    Nix(NixArgs),
+    Flake(FlakeArgs),
+    Store(StoreArgs),
+    Explain(ExplainArgs),
+    Init(InitArgs),
+    Version(VersionArgs),
 }
 
 #[derive(Args, Debug)]
@@ -87,24 +178,69 @@ pub enum OsRebuildType {
     Test(OsRebuildArgs),
     /// Build the new configuration
     Build(OsRebuildArgs),
+    /// Only build the new configuration and print its store path to stdout, for scripts
+    BuildOnly(OsBuildOnlyArgs),
     /// Open default editor in the flake directory
     Edit(OsEditArgs),
-    /// Show an overview of the system's info
-    #[command(hide = true)]
+    /// List the generations of the system profile
+    Generations(OsGenerationsArgs),
+    /// Compare the closure sizes of generations
+    Sizes(OsSizesArgs),
+    /// Show the version and revisions the running system was built from
     Info,
 }
 
 #[derive(Debug, Args)]
 pub struct OsEditArgs {
+    /// Only print the editor invocation, without launching it
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
     #[arg(env = "NH_OS_FLAKE", value_hint = clap::ValueHint::DirPath)]
     pub flakeref: FlakeRef,
 }
 
+#[derive(Debug, Args)]
+pub struct OsGenerationsArgs {
+    /// Profile to list the generations of
+    #[arg(long, default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+
+    /// Browse the generations to diff or roll back to one. Needs nh to be built with the tui feature
+    #[arg(long, short)]
+    pub interactive: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsBuildOnlyArgs {
+    #[command(flatten)]
+    pub rebuild: OsRebuildArgs,
+
+    /// Link the result to PATH, instead of leaving it unrooted
+    #[arg(long, value_name = "PATH")]
+    pub out_link: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct OsSizesArgs {
+    /// Numbers of the generations to compare, like 140 143
+    #[arg(required = true)]
+    pub generations: Vec<u32>,
+
+    /// Profile the generations belong to
+    #[arg(long, default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
 #[derive(Debug, Args)]
 pub struct OsRebuildArgs {
     #[command(flatten)]
     pub common: CommonRebuildArgs,
 
+    /// Run even as root, like in a container. nh elevates by itself when it activates
+    #[arg(long, short = 'R')]
+    pub bypass_root_check: bool,
+
     /// Output to choose from the flakeref. Hostname is used by default
     #[arg(long, short = 'H', global = true)]
     pub hostname: Option<OsString>,
@@ -117,6 +253,85 @@ pub struct OsRebuildArgs {
     #[arg(long, short = 'S')]
     pub no_specialisation: bool,
 
+    /// Pass a raw action to switch-to-configuration instead of the default activation steps
+    ///
+    /// The action is forwarded verbatim and is not validated
+    #[arg(long, value_name = "ACTION")]
+    pub activation_action: Option<String>,
+
+    /// Description of the new generation, shown by nh os generations
+    #[arg(long, short = 'm')]
+    pub message: Option<String>,
+
+    /// Verify the signatures of the built closure with nix store verify before activating it
+    #[arg(long)]
+    pub verify: bool,
+
+    /// After activating, report units that started failing and exit with an error if any did
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Don't restart these units during the activation, reporting them afterwards instead.
+    /// Units without a type are services
+    #[arg(long, value_name = "UNIT", value_delimiter = ',', value_parser = crate::systemd::parse_unit)]
+    pub skip_restart: Vec<String>,
+
+    /// Don't restart any unit during the activation, reporting the ones that would have been
+    #[arg(long, conflicts_with = "skip_restart")]
+    pub no_restart: bool,
+
+    /// Reinstall the bootloader instead of only adding the configuration to it, for switch and
+    /// boot
+    #[arg(long)]
+    pub install_bootloader: bool,
+
+    /// Don't touch the bootloader, only pointing the system profile to the configuration. For
+    /// systems whose bootloader is managed externally. Only supported by switch and boot
+    #[arg(long, conflicts_with = "install_bootloader")]
+    pub no_bootloader: bool,
+
+    /// Reboot after adding the configuration to the bootloader. Only supported by boot
+    #[arg(long)]
+    pub reboot: bool,
+
+    /// Wait for other activations in progress to finish, instead of failing
+    #[arg(long, overrides_with = "no_wait")]
+    pub wait: bool,
+
+    /// Fail if another activation is in progress (default)
+    #[arg(long, overrides_with = "wait")]
+    pub no_wait: bool,
+
+    /// If the activation fails, keep the built configuration at ./result-failed
+    #[arg(long)]
+    pub keep_failed_result: bool,
+
+    /// Activate the configuration even if it's already the current one
+    #[arg(long)]
+    pub force: bool,
+
+    /// Build the configuration for each of these systems, side by side in separate nix builds.
+    /// Only supported by build
+    #[arg(
+        long = "for",
+        value_name = "SYSTEMS",
+        value_delimiter = ',',
+        conflicts_with = "system"
+    )]
+    pub for_systems: Vec<String>,
+
+    /// Roll back to the previous configuration unless it's confirmed within SECONDS
+    ///
+    /// Useful when changing the network configuration of a remote machine. Only supported by test
+    /// and switch
+    #[arg(long, value_name = "SECONDS")]
+    pub rollback_in: Option<u64>,
+
+    /// Build the configuration of the host from this flake instead, like an alternate config
+    /// repository. The hostname and config files still come from the usual flake
+    #[arg(long, value_name = "FLAKEREF", value_parser = crate::flake::parse_override)]
+    pub override_flake: Option<FlakeRef>,
+
     /// Extra arguments passed to nix build
     #[arg(last = true)]
     pub extra_args: Vec<String>,
@@ -135,24 +350,282 @@ pub struct CommonRebuildArgs {
     #[arg(long, short)]
     pub ask: bool,
 
+    /// Assume yes on every confirmation prompt
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
     /// Update flake inputs before building specified configuration
     #[arg(long, short = 'u')]
     pub update: bool,
 
-    /// Don't use nix-output-monitor for the build process
+    /// Recreate flake.lock from scratch while building. This overwrites the existing lock file
+    #[arg(long)]
+    pub recreate_lock_file: bool,
+
+    /// Consider all previously downloaded flake inputs as stale
+    #[arg(long)]
+    pub refresh: bool,
+
+    /// Refuse to build if the git work tree of the flake has uncommitted changes, so that the
+    /// deployed configuration is recorded in git
+    #[arg(long, conflicts_with = "update")]
+    pub require_clean: bool,
+
+    /// Let nix write flake.lock even if the flake directory doesn't look writable
+    #[arg(long)]
+    pub force_write_lock_file: bool,
+
+    /// When to use nix-output-monitor for the build process
+    ///
+    /// auto uses it if it's installed and the output is a terminal. Defaults to the nom of the
+    /// config files, or auto
+    #[arg(long, value_enum, env = "NH_NOM")]
+    pub nom: Option<NomMode>,
+
+    /// Don't use nix-output-monitor for the build process, same as --nom never
     #[arg(long)]
     pub no_nom: bool,
 
+    /// Print full build logs, and the log of the failed derivation on errors
+    #[arg(long, short = 'L')]
+    pub print_build_logs: bool,
+
+    /// After building, print the N derivations that took the longest to build
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    pub build_report: Option<usize>,
+
+    /// Only evaluate the configuration and print its derivation, which catches evaluation errors
+    /// without building anything
+    #[arg(long, conflicts_with = "dry")]
+    pub eval_only: bool,
+
+    /// Only print how many derivations would be built and how many paths would be fetched
+    #[arg(long, conflicts_with = "eval_only")]
+    pub plan: bool,
+
+    /// Like --plan, also listing the derivations and paths
+    #[arg(long, conflicts_with = "eval_only")]
+    pub plan_paths: bool,
+
+    /// After building, print the NAR hash of every output, to compare builds across machines
+    #[arg(long, conflicts_with = "eval_only")]
+    pub report_hashes: bool,
+
+    /// Report the progress of the build as JSON lines on stderr instead of the logs of nix, for
+    /// editors and other tools. Every line has a phase, current and total counts, a message and
+    /// a timestamp
+    #[arg(long)]
+    pub progress_json: bool,
+
+    /// Where the stderr of nix goes when not using nom: through nh with the logs, straight to
+    /// the terminal, or kept back and only printed if the build fails
+    #[arg(long, value_enum, default_value_t = crate::commands::StderrPolicy::Merge)]
+    pub nix_stderr: crate::commands::StderrPolicy,
+
+    /// Don't repeat the warnings of nix after the build
+    #[arg(long)]
+    pub no_warning_summary: bool,
+
+    /// Retry the build with nix-command and flakes enabled if they are disabled in nix.conf
+    #[arg(long)]
+    pub auto_features: bool,
+
+    /// Retry the build once with a single job if a builder runs out of memory
+    #[arg(long)]
+    pub auto_recover: bool,
+
+    /// Write the raw internal-json events of the build to FILE, one per line. Needs nom
+    #[arg(long, value_name = "FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Let remote builders fetch from substituters instead of copying paths from this machine
+    #[arg(long)]
+    pub builders_use_substitutes: bool,
+
+    /// Build into an alternate store. Only supported when building without activation
+    #[arg(long, value_name = "URL")]
+    pub store: Option<String>,
+
+    /// System to build for, like aarch64-linux. Requires a remote builder or binfmt emulation
+    #[arg(long)]
+    pub system: Option<String>,
+
+    /// Skip every step that isn't needed to activate: implies --no-preflight, --no-diff and --no-link
+    #[arg(long)]
+    pub fast: bool,
+
+    /// Don't check that the configuration exists before building it
+    #[arg(long)]
+    pub no_preflight: bool,
+
+    /// Don't compare the changes with the diff provider
+    #[arg(long)]
+    pub no_diff: bool,
+
+    /// Don't create an out-link for the build result
+    #[arg(long)]
+    pub no_link: bool,
+
     /// Closure diff provider
     ///
-    /// Default is "nvd diff", but "nix store diff-closures" is also supported
+    /// Default is "nvd diff", falling back to "nix store diff-closures" if nvd isn't installed.
+    /// Any other program is called with both store paths as arguments
     #[arg(
-        long,
+        long = "diff-provider",
+        visible_alias = "diff-tool",
         short = 'D',
         env = "NH_DIFF_PROVIDER",
         default_value = "nvd diff"
     )]
-    pub diff_provider: String,
+    pub diff_tool: DiffTool,
+
+    /// Extra flags for every nix invocation, split like a shell would
+    ///
+    /// They are passed before the extra arguments given after --
+    #[arg(
+        long,
+        env = "NH_NIX_FLAGS",
+        value_name = "FLAGS",
+        allow_hyphen_values = true
+    )]
+    pub nix_flags: Option<NixFlags>,
+
+    #[command(flatten)]
+    pub hooks: HookArgs,
+}
+
+/// Shell commands run around the rebuild, with NH_PHASE and NH_STORE_PATH set
+///
+/// A failing pre-hook aborts the rebuild, a failing post-hook only prints a warning.
+#[derive(Debug, Args, Default)]
+pub struct HookArgs {
+    /// Shell command to run before building
+    #[arg(long, env = "NH_PRE_BUILD_HOOK", value_name = "COMMAND")]
+    pub pre_build: Option<String>,
+
+    /// Shell command to run after a successful build
+    #[arg(long, env = "NH_POST_BUILD_HOOK", value_name = "COMMAND")]
+    pub post_build: Option<String>,
+
+    /// Shell command to run before activating
+    #[arg(long, env = "NH_PRE_ACTIVATE_HOOK", value_name = "COMMAND")]
+    pub pre_activate: Option<String>,
+
+    /// Shell command to run after a successful activation
+    #[arg(long, env = "NH_POST_ACTIVATE_HOOK", value_name = "COMMAND")]
+    pub post_activate: Option<String>,
+}
+
+/// Optional steps of a rebuild, resolved from the flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebuildPhases {
+    pub preflight: bool,
+    pub diff: bool,
+    pub link: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NomMode {
+    Auto,
+    /// Fail if nom isn't installed
+    Always,
+    Never,
+}
+
+impl NomMode {
+    /// Whether to build with nom, given if it's installed and if the output is a terminal
+    pub fn resolve_with(self, installed: bool, tty: bool) -> Result<bool> {
+        match self {
+            NomMode::Auto => Ok(installed && tty),
+            NomMode::Always if !installed => {
+                color_eyre::eyre::bail!("--nom always was given, but nom isn't installed")
+            }
+            NomMode::Always => Ok(true),
+            NomMode::Never => Ok(false),
+        }
+    }
+}
+
+impl CommonRebuildArgs {
+    /// Whether to build with nom, from --nom and --no-nom, falling back to `config`
+    pub fn use_nom(&self, config: &Config) -> Result<bool> {
+        use std::io::IsTerminal;
+
+        if self.no_nom {
+            return Ok(false);
+        }
+
+        crate::config::pick(self.nom, config.nom, NomMode::Auto)
+            .resolve_with(crate::util::in_path("nom"), std::io::stderr().is_terminal())
+    }
+
+    /// Whether nix should be kept from writing the lock file of `flakeref`
+    pub fn read_only_lock_file(&self, flakeref: &FlakeRef) -> bool {
+        if self.force_write_lock_file {
+            return false;
+        }
+
+        match crate::flake::local_dir(flakeref) {
+            Some(dir) if !crate::flake::is_writable(&dir) => {
+                tracing::info!(
+                    "{} is not writable, building with --no-write-lock-file",
+                    dir.display()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fails with the uncommitted files of the flake if --require-clean is set
+    pub fn check_clean(&self, flakeref: &FlakeRef) -> Result<()> {
+        if !self.require_clean {
+            return Ok(());
+        }
+
+        let Some(dir) = crate::flake::local_dir(flakeref) else {
+            tracing::debug!("{} isn't a local flake, nothing to check", flakeref.deref());
+            return Ok(());
+        };
+
+        match crate::flake::dirty_files(&dir)? {
+            None => tracing::warn!(
+                "{} isn't in a git repository, treating it as clean",
+                dir.display()
+            ),
+            Some(dirty) if !dirty.is_empty() => color_eyre::eyre::bail!(
+                "{} has uncommitted changes:\n{}",
+                dir.display(),
+                dirty
+                    .iter()
+                    .map(|path| format!("  {path}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+            Some(_) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Whether the build is only planned, or only evaluated, so there is nothing to activate
+    pub fn builds_nothing(&self) -> bool {
+        self.eval_only || self.plan || self.plan_paths
+    }
+
+    /// Flags from --nix-flags or NH_NIX_FLAGS
+    pub fn nix_flags(&self) -> &[String] {
+        self.nix_flags.as_ref().map_or(&[], |flags| &flags.0)
+    }
+
+    pub fn phases(&self) -> RebuildPhases {
+        RebuildPhases {
+            preflight: !(self.fast || self.no_preflight),
+            diff: !(self.fast || self.no_diff),
+            link: !(self.fast || self.no_link),
+        }
+    }
 }
 
 #[derive(Args, Debug)]
@@ -191,6 +664,10 @@ pub enum CleanMode {
     User(CleanArgs),
     /// Cleans a specific profile
     Profile(CleanProfileArgs),
+    /// Wipes the history of the current user's home-manager profile
+    Home(CleanWipeArgs),
+    /// Wipes the history of a specific profile with nix profile wipe-history
+    Wipe(CleanWipeProfileArgs),
 }
 
 #[derive(Args, Clone, Debug)]
@@ -232,6 +709,31 @@ pub struct CleanProfileArgs {
     pub profile: PathBuf,
 }
 
+#[derive(Args, Clone, Debug)]
+pub struct CleanWipeArgs {
+    #[arg(long)]
+    /// Only wipe generations older than this, rounded up to days
+    pub older_than: Option<humantime::Duration>,
+
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Ask for confimation
+    #[arg(long, short)]
+    pub ask: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CleanWipeProfileArgs {
+    #[command(flatten)]
+    pub common: CleanWipeArgs,
+
+    /// Profile to wipe the history of
+    #[arg(long)]
+    pub profile: PathBuf,
+}
+
 #[derive(Debug, Args)]
 /// Home-manager functionality
 pub struct HomeArgs {
@@ -271,6 +773,11 @@ pub struct HomeRebuildArgs {
     #[arg(long, short)]
     pub configuration: Option<String>,
 
+    /// Build this attribute of the configuration instead of its activation package, like
+    /// config.home.path, and print its store path. Only supported by build
+    #[arg(long, short = 'A', value_name = "ATTR")]
+    pub attr: Option<String>,
+
     /// Extra arguments passed to nix build
     #[arg(last = true)]
     pub extra_args: Vec<String>,
@@ -278,13 +785,17 @@ pub struct HomeRebuildArgs {
     /// Move existing files by backing up with the extension
     #[arg(long, short = 'b')]
     pub backup_extension: Option<String>,
-    
+
     #[arg(env = "NH_HOME_FLAKE", value_hint = clap::ValueHint::DirPath)]
     pub flakeref: Option<FlakeRef>,
 }
 
 #[derive(Debug, Args)]
 pub struct HomeEditArgs {
+    /// Only print the editor invocation, without launching it
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
     #[arg(env = "NH_HOME_FLAKE", value_hint = clap::ValueHint::DirPath)]
     pub flakeref: FlakeRef,
 }
@@ -297,3 +808,196 @@ pub struct CompletionArgs {
     pub shell: clap_complete::Shell,
 }
 
+#[derive(Args, Debug)]
+/// Checks on the inputs of a flake
+pub struct FlakeArgs {
+    #[command(subcommand)]
+    pub subcommand: FlakeSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FlakeSubcommand {
+    /// Report the inputs of flake.lock that have newer commits upstream, without updating it.
+    /// Exits with an error if any does
+    Status(FlakeStatusArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct FlakeStatusArgs {
+    #[arg(default_value = ".", value_hint = clap::ValueHint::DirPath)]
+    pub flakeref: FlakeRef,
+}
+
+#[derive(Args, Debug)]
+/// Summarize the differences between two closures, grouped into added, removed and changed
+/// packages
+pub struct ExplainArgs {
+    /// Old store path, like /run/current-system
+    pub old: PathBuf,
+
+    /// New store path, like ./result
+    pub new: PathBuf,
+}
+
+#[derive(Args, Debug)]
+/// Write a minimal flake.nix to the current directory, if there is none yet
+pub struct InitArgs {
+    /// Kind of configuration to scaffold
+    #[arg(long, value_enum, default_value_t = InitTemplate::Nixos)]
+    pub template: InitTemplate,
+
+    /// Name of the configuration, defaults to the hostname
+    #[arg(long, short = 'H')]
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InitTemplate {
+    /// nixosConfigurations, for nh os
+    Nixos,
+    /// homeConfigurations of home-manager, for nh home, named user@hostname
+    Home,
+    /// darwinConfigurations of nix-darwin
+    Darwin,
+}
+
+#[derive(Args, Debug)]
+/// Maintenance of the nix store
+pub struct StoreArgs {
+    #[command(subcommand)]
+    pub subcommand: StoreSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StoreSubcommand {
+    /// Save space by hard-linking identical files in the store, reporting the space freed
+    Optimise(StoreOptimiseArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct StoreOptimiseArgs {
+    /// Only print the nix invocation, as nix can't estimate the savings
+    #[arg(long, short = 'n', visible_alias = "dry-run")]
+    pub dry: bool,
+}
+
+#[derive(Args, Debug)]
+/// Print the versions of nh and nix, and whether nom and nvd are installed
+pub struct VersionArgs {
+    /// Print the versions as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+/// Run a nix command, like nh nix -- store gc
+pub struct NixArgs {
+    /// Only print the nix invocation, without running it
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Run nix with sudo
+    #[arg(long, short = 'e')]
+    pub elevate: bool,
+
+    /// Arguments passed to nix, without being interpreted
+    #[arg(last = true, required = true)]
+    pub args: Vec<OsString>,
+}
+
+#[test]
+fn test_fast_phases() {
+    let phases = |args: &[&str]| {
+        let parsed = NHParser::parse_from(["nh", "os", "switch"].iter().chain(args));
+        let NHCommand::Os(OsArgs {
+            action: OsRebuildType::Switch(args),
+        }) = parsed.command
+        else {
+            panic!("Expected nh os switch");
+        };
+        args.common.phases()
+    };
+
+    assert_eq!(
+        phases(&[]),
+        RebuildPhases {
+            preflight: true,
+            diff: true,
+            link: true
+        }
+    );
+    assert_eq!(
+        phases(&["--fast"]),
+        RebuildPhases {
+            preflight: false,
+            diff: false,
+            link: false
+        }
+    );
+    assert_eq!(
+        phases(&["--no-diff"]),
+        RebuildPhases {
+            preflight: true,
+            diff: false,
+            link: true
+        }
+    );
+}
+
+#[test]
+fn test_nom_mode() {
+    for (installed, tty) in [(true, true), (true, false), (false, true), (false, false)] {
+        assert_eq!(
+            NomMode::Auto.resolve_with(installed, tty).unwrap(),
+            installed && tty
+        );
+        assert!(!NomMode::Never.resolve_with(installed, tty).unwrap());
+        assert_eq!(
+            NomMode::Always.resolve_with(installed, tty).ok(),
+            installed.then_some(true)
+        );
+    }
+
+    let parsed = NHParser::parse_from(["nh", "os", "build", "--nom", "always", "--no-nom"]);
+    let NHCommand::Os(OsArgs {
+        action: OsRebuildType::Build(args),
+    }) = parsed.command
+    else {
+        panic!("Expected nh os build");
+    };
+    assert_eq!(args.common.nom, Some(NomMode::Always));
+    assert!(!args.common.use_nom(&Config::default()).unwrap());
+}
+
+#[test]
+fn test_flake_kind() {
+    let kind = |s: &str| FlakeRef::from(s).kind();
+
+    assert_eq!(
+        kind("nixpkgs#hello"),
+        FlakeKind::Registry(String::from("nixpkgs"))
+    );
+    assert_eq!(
+        kind("my-flake"),
+        FlakeKind::Registry(String::from("my-flake"))
+    );
+    assert_eq!(
+        kind("nixpkgs/nixos-24.05#hello"),
+        FlakeKind::Registry(String::from("nixpkgs"))
+    );
+    assert_eq!(
+        kind("flake:nixpkgs#hello"),
+        FlakeKind::Registry(String::from("nixpkgs"))
+    );
+
+    assert_eq!(kind(".#host"), FlakeKind::Local(PathBuf::from(".")));
+    assert_eq!(kind("/abs#a"), FlakeKind::Local(PathBuf::from("/abs")));
+    assert_eq!(
+        kind("path:/etc/nixos?dir=sub#a"),
+        FlakeKind::Local(PathBuf::from("/etc/nixos"))
+    );
+
+    assert_eq!(kind("github:o/r#a"), FlakeKind::Remote);
+    assert_eq!(kind("git+https://example.org/r"), FlakeKind::Remote);
+    assert_eq!(kind("https://example.org/flake.tar.gz"), FlakeKind::Remote);
+}
@@ -0,0 +1,388 @@
+//! Parsing for nix's `--log-format internal-json` stream
+//!
+//! Every line emitted by nix in this mode looks like `@nix {...}`, where the payload describes
+//! an activity being started or stopped, a result of an activity, or a plain log message.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+
+const PREFIX: &str = "@nix ";
+
+/// Activity type of a derivation being built
+const ACTIVITY_BUILD: u64 = 105;
+
+/// Result type of a line of build output
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+
+static ANSI_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+static FAILED_DRV_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:builder for|Cannot build) '(/nix/store/[^']+\.drv)'").unwrap());
+static MISSING_FEATURE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"experimental Nix feature '([^']+)' is disabled").unwrap());
+static OUT_OF_MEMORY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)out of memory|signal 9\b|^\s*killed\b|\bcannot allocate memory").unwrap()
+});
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Event {
+    Msg {
+        level: u8,
+        msg: String,
+    },
+    Start {
+        id: u64,
+        #[serde(rename = "type", default)]
+        activity_type: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        result_type: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// JSON object of an event line, without the `@nix` prefix
+pub fn payload(line: &str) -> Option<&str> {
+    line.trim_end().strip_prefix(PREFIX)
+}
+
+/// Parses a single line of the stream, returning `None` for lines that aren't nix events
+pub fn parse_line(line: &str) -> Option<Event> {
+    serde_json::from_str(payload(line)?).ok()
+}
+
+/// Removes the terminal color codes that nix embeds into its messages
+pub fn strip_ansi(text: &str) -> String {
+    ANSI_REGEX.replace_all(text, "").into_owned()
+}
+
+/// Returns the derivation that failed to build, if the event reports a build failure
+pub fn failed_derivation(event: &Event) -> Option<String> {
+    let Event::Msg { level: 0, msg } = event else {
+        return None;
+    };
+
+    FAILED_DRV_REGEX
+        .captures(&strip_ansi(msg))
+        .map(|caps| caps[1].to_string())
+}
+
+/// Experimental feature that nix refused to use, from its error message
+pub fn missing_feature(msg: &str) -> Option<String> {
+    MISSING_FEATURE_REGEX
+        .captures(&strip_ansi(msg))
+        .map(|caps| caps[1].to_string())
+}
+
+/// Text of a warning of nix, without the `warning:` prefix
+pub fn warning(msg: &str) -> Option<String> {
+    strip_ansi(msg)
+        .trim()
+        .strip_prefix("warning:")
+        .map(|text| text.trim().to_string())
+}
+
+/// Whether the message hints at a builder killed for running out of memory
+pub fn is_out_of_memory(msg: &str) -> bool {
+    OUT_OF_MEMORY_REGEX.is_match(&strip_ansi(msg))
+}
+
+/// Information gathered from the output of a nix build
+#[derive(Debug, Default)]
+pub struct BuildLog {
+    /// First derivation reported as failed
+    pub failed_derivation: Option<String>,
+    /// Output paths printed by `--print-out-paths`
+    pub out_paths: Vec<PathBuf>,
+    /// How long each derivation took to build
+    pub durations: HashMap<String, Duration>,
+    /// Experimental features that have to be enabled for the build
+    pub missing_features: Vec<String>,
+    /// Whether a builder seems to have run out of memory
+    pub out_of_memory: bool,
+    /// Warnings of nix in the order they were first emitted, without duplicates
+    pub warnings: Vec<String>,
+    /// Builds that have started but not stopped yet, by activity id
+    running: HashMap<u64, (String, Instant)>,
+}
+
+impl BuildLog {
+    /// Consumes a line of nix's output, which can either be an event or an output path
+    pub fn observe(&mut self, line: &str) {
+        self.observe_at(line, Instant::now())
+    }
+
+    /// Like [`BuildLog::observe`], for a line received at `at`
+    pub fn observe_at(&mut self, line: &str, at: Instant) {
+        let Some(event) = parse_line(line) else {
+            if line.starts_with("/nix/store/") {
+                self.out_paths.push(PathBuf::from(line.trim_end()));
+            } else {
+                self.observe_message(line);
+                self.add_warning(line);
+            }
+            return;
+        };
+
+        if self.failed_derivation.is_none() {
+            self.failed_derivation = failed_derivation(&event);
+        }
+
+        match &event {
+            Event::Msg { level: 0, msg } => self.observe_message(msg),
+            Event::Msg { level: 1, msg } => self.add_warning(msg),
+            Event::Result {
+                result_type: RESULT_BUILD_LOG_LINE,
+                fields,
+                ..
+            } => {
+                if let Some(line) = fields.first().and_then(|f| f.as_str()) {
+                    self.out_of_memory |= is_out_of_memory(line);
+                }
+            }
+            _ => {}
+        }
+
+        match event {
+            Event::Start {
+                id,
+                activity_type: ACTIVITY_BUILD,
+                fields,
+                ..
+            } => {
+                if let Some(drv) = fields.first().and_then(|f| f.as_str()) {
+                    self.running.insert(id, (drv.to_string(), at));
+                }
+            }
+            Event::Stop { id } => {
+                if let Some((drv, started)) = self.running.remove(&id) {
+                    self.durations.insert(drv, at.duration_since(started));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Looks for the errors nh can explain in a message of nix
+    fn observe_message(&mut self, msg: &str) {
+        if let Some(feature) = missing_feature(msg) {
+            if !self.missing_features.contains(&feature) {
+                self.missing_features.push(feature);
+            }
+        }
+        self.out_of_memory |= is_out_of_memory(msg);
+    }
+
+    fn add_warning(&mut self, msg: &str) {
+        if let Some(warning) = warning(msg) {
+            if !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
+    }
+
+    /// The `n` derivations that took the longest to build, slowest first
+    pub fn slowest(&self, n: usize) -> Vec<(&str, Duration)> {
+        let mut durations: Vec<_> = self
+            .durations
+            .iter()
+            .map(|(drv, duration)| (drv.as_str(), *duration))
+            .collect();
+        durations.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        durations.truncate(n);
+        durations
+    }
+}
+
+#[test]
+fn test_build_durations() {
+    let start = Instant::now();
+    let transcript = [
+        (
+            0,
+            r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building '/nix/store/aaa-foo.drv'","fields":["/nix/store/aaa-foo.drv","",1,1]}"#,
+        ),
+        (
+            1,
+            r#"@nix {"action":"start","id":2,"level":3,"type":105,"text":"building '/nix/store/bbb-bar.drv'","fields":["/nix/store/bbb-bar.drv","",1,1]}"#,
+        ),
+        (
+            2,
+            r#"@nix {"action":"start","id":3,"level":4,"type":100,"text":"copying path","fields":["/nix/store/ccc-baz","",""]}"#,
+        ),
+        (3, r#"@nix {"action":"stop","id":3}"#),
+        (5, r#"@nix {"action":"stop","id":1}"#),
+        (31, r#"@nix {"action":"stop","id":2}"#),
+    ];
+
+    let mut log = BuildLog::default();
+    for (secs, line) in transcript {
+        log.observe_at(line, start + Duration::from_secs(secs));
+    }
+
+    assert_eq!(log.durations.len(), 2);
+    assert_eq!(
+        log.slowest(10),
+        [
+            ("/nix/store/bbb-bar.drv", Duration::from_secs(30)),
+            ("/nix/store/aaa-foo.drv", Duration::from_secs(5)),
+        ]
+    );
+    assert_eq!(
+        log.slowest(1),
+        [("/nix/store/bbb-bar.drv", Duration::from_secs(30))]
+    );
+}
+
+#[test]
+fn test_build_log_out_paths() {
+    let mut log = BuildLog::default();
+    for line in [
+        r#"@nix {"action":"stop","id":1}"#,
+        "/nix/store/abc-nixos-system-host\n",
+        "/nix/store/def-man-pages",
+    ] {
+        log.observe(line);
+    }
+
+    assert_eq!(
+        log.out_paths,
+        [
+            PathBuf::from("/nix/store/abc-nixos-system-host"),
+            PathBuf::from("/nix/store/def-man-pages"),
+        ]
+    );
+    assert_eq!(log.failed_derivation, None);
+}
+
+#[test]
+fn test_build_log_multiple_installables() {
+    // stdout and stderr are merged without nom, like for --progress-json with --nix-stderr merge
+    let mut log = BuildLog::default();
+    for line in [
+        r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building '/nix/store/aaa-foo.drv'","fields":["/nix/store/aaa-foo.drv","",1,1]}"#,
+        r#"@nix {"action":"stop","id":1}"#,
+        "/nix/store/aaa-foo",
+        "/nix/store/bbb-bar-bin\n",
+        r#"@nix {"action":"msg","level":3,"msg":"some message"}"#,
+        "/nix/store/bbb-bar-man\n",
+    ] {
+        log.observe(line);
+    }
+
+    assert_eq!(
+        log.out_paths,
+        [
+            PathBuf::from("/nix/store/aaa-foo"),
+            PathBuf::from("/nix/store/bbb-bar-bin"),
+            PathBuf::from("/nix/store/bbb-bar-man"),
+        ]
+    );
+}
+
+#[test]
+fn test_missing_features() {
+    let mut log = BuildLog::default();
+    for line in [
+        "\u{1b}[31;1merror:\u{1b}[0m experimental Nix feature 'nix-command' is disabled; add '--extra-experimental-features nix-command' to enable it\n",
+        r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it"}"#,
+        "error: experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it",
+        "error: builder for '/nix/store/abc-hello.drv' failed with exit code 1",
+    ] {
+        log.observe(line);
+    }
+
+    assert_eq!(log.missing_features, ["nix-command", "flakes"]);
+}
+
+#[test]
+fn test_out_of_memory() {
+    let transcript = [
+        r#"@nix {"action":"start","id":7,"level":3,"type":105,"text":"building '/nix/store/abc-chromium.drv'","fields":["/nix/store/abc-chromium.drv","",1,1]}"#,
+        r#"@nix {"action":"result","id":7,"type":101,"fields":["[4123/51234] CXX obj/v8/compiler.o"]}"#,
+        r#"@nix {"action":"result","id":7,"type":101,"fields":["c++: fatal error: Killed signal terminated program cc1plus"]}"#,
+        r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m builder for '/nix/store/abc-chromium.drv' failed due to signal 9 (Killed)"}"#,
+    ];
+
+    let mut log = BuildLog::default();
+    for line in &transcript[..2] {
+        log.observe(line);
+    }
+    assert!(!log.out_of_memory);
+
+    for line in &transcript[2..] {
+        log.observe(line);
+    }
+    assert!(log.out_of_memory);
+
+    let mut log = BuildLog::default();
+    log.observe("error: writing to file: Cannot allocate memory");
+    assert!(log.out_of_memory);
+
+    let mut log = BuildLog::default();
+    log.observe("error: builder for '/nix/store/abc-hello.drv' failed with exit code 1");
+    assert!(!log.out_of_memory);
+}
+
+#[test]
+fn test_warnings() {
+    let mut log = BuildLog::default();
+    for line in [
+        r#"@nix {"action":"msg","level":1,"msg":"\u001b[35;1mwarning:\u001b[0m Git tree '/etc/nixos' is dirty"}"#,
+        r#"@nix {"action":"msg","level":1,"msg":"\u001b[35;1mwarning:\u001b[0m 'system' has been renamed to/replaced by 'stdenv.hostPlatform.system'"}"#,
+        r#"@nix {"action":"msg","level":3,"msg":"evaluating derivation 'toplevel'"}"#,
+        r#"@nix {"action":"msg","level":1,"msg":"\u001b[35;1mwarning:\u001b[0m Git tree '/etc/nixos' is dirty"}"#,
+        "warning: 'system' has been renamed to/replaced by 'stdenv.hostPlatform.system'\n",
+        "warning: ignoring untrusted substituter 'https://cache.example.org'\n",
+        "these 3 derivations will be built:\n",
+    ] {
+        log.observe(line);
+    }
+
+    assert_eq!(
+        log.warnings,
+        [
+            "Git tree '/etc/nixos' is dirty",
+            "'system' has been renamed to/replaced by 'stdenv.hostPlatform.system'",
+            "ignoring untrusted substituter 'https://cache.example.org'",
+        ]
+    );
+}
+
+#[test]
+fn test_failed_derivation() {
+    let line = r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m builder for '\u001b[35;1m/nix/store/abc-hello-2.12.drv\u001b[0m' failed with exit code 1"}"#;
+    let event = parse_line(line).unwrap();
+    assert_eq!(
+        failed_derivation(&event).as_deref(),
+        Some("/nix/store/abc-hello-2.12.drv")
+    );
+
+    let line = r#"@nix {"action":"msg","level":0,"msg":"error: Cannot build '/nix/store/abc-hello-2.12.drv'.\n       Reason: builder failed with exit code 1."}"#;
+    let event = parse_line(line).unwrap();
+    assert_eq!(
+        failed_derivation(&event).as_deref(),
+        Some("/nix/store/abc-hello-2.12.drv")
+    );
+
+    let line = r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building '/nix/store/abc-hello-2.12.drv'","fields":["/nix/store/abc-hello-2.12.drv","",1,1]}"#;
+    assert_eq!(failed_derivation(&parse_line(line).unwrap()), None);
+    assert_eq!(parse_line("not a nix event"), None);
+}
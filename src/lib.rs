@@ -0,0 +1,179 @@
+//! nh as a library, for tools that drive rebuilds without going through the command line
+//!
+//! The `nh` binary is a thin wrapper around [`run_cli`].
+
+mod clean;
+mod commands;
+mod completion;
+mod config;
+mod diff;
+mod elevate;
+mod explain;
+mod flake;
+mod flake_status;
+mod generations;
+mod home;
+mod hooks;
+mod init;
+mod interface;
+mod internal_json;
+mod lock;
+mod logging;
+mod nixos;
+mod notify;
+mod passthrough;
+mod plan;
+mod prefix;
+mod progress;
+mod search;
+mod signals;
+mod state;
+mod store;
+mod systemd;
+mod tui;
+mod util;
+mod version;
+
+use clap::{Args, FromArgMatches};
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::interface::NHParser;
+use crate::interface::NHRunnable;
+use crate::interface::{FlakeRef, OsRebuildArgs, OsRebuildType};
+
+pub use crate::commands::StderrPolicy;
+pub use crate::nixos::SwitchOutcome;
+
+pub const NH_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Parses the command line and runs the command, like the `nh` binary
+pub fn run_cli() -> Result<()> {
+    let args = <NHParser as clap::Parser>::parse();
+    crate::logging::setup_logging(args.verbose, args.quiet)?;
+    crate::commands::set_show_command(args.show_command);
+    crate::util::configure_nix(args.nix_bin.clone(), args.nix_variant);
+    crate::prefix::set_output_prefix(args.output_prefix.clone());
+    let config = crate::config::Config::global()?;
+    crate::elevate::configure(crate::elevate::Elevation::new(
+        crate::config::pick(
+            args.elevation_program,
+            config.elevation_program,
+            Default::default(),
+        ),
+        args.askpass.clone().or(config.askpass),
+    ));
+    crate::signals::forward_termination()?;
+    tracing::debug!(?args);
+
+    let start = std::time::Instant::now();
+    let result = args.command.run();
+    if args.notify {
+        // The binary is usually called by its full store path, which says little
+        let command: Vec<String> = std::iter::once(String::from("nh"))
+            .chain(std::env::args().skip(1))
+            .collect();
+        crate::notify::notify(
+            config.ntfy_topic.as_deref(),
+            &crate::notify::Notification::finished(
+                &crate::commands::shell_join(&command),
+                &result,
+                start.elapsed(),
+            ),
+        );
+    }
+    result
+}
+
+/// What [`switch`] does with the built configuration, like the subcommands of `nh os`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SwitchMode {
+    /// Activate it and make it the boot default
+    #[default]
+    Switch,
+    /// Make it the boot default
+    Boot,
+    /// Activate it
+    Test,
+    /// Only build it
+    Build,
+}
+
+/// Options of [`switch`], the settings of `nh os` that make sense outside of a terminal
+///
+/// Everything else keeps the default of the command line, so the build never prompts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SwitchOptions {
+    /// Flake containing the configuration, like `/etc/nixos`
+    pub flake: String,
+    /// Entry of nixosConfigurations to build, the hostname by default
+    pub hostname: Option<String>,
+    pub mode: SwitchMode,
+    /// Build and diff the configuration, without activating it
+    pub dry: bool,
+    /// Update the flake inputs before building
+    pub update: bool,
+    pub specialisation: Option<String>,
+    /// Don't check that the configuration exists before building it
+    pub no_preflight: bool,
+    /// Don't compare the configuration with the running one
+    pub no_diff: bool,
+    /// Extra arguments passed to nix build
+    pub extra_args: Vec<String>,
+    /// Where the stderr of nix goes when not using nom
+    pub nix_stderr: StderrPolicy,
+    /// nix binary to run, like `--nix-bin`. Only the first one given in a process applies
+    pub nix_bin: Option<String>,
+    /// Run even as root, which `nh os` refuses, like in a container building for another host
+    pub allow_root: bool,
+}
+
+/// Builds and activates a NixOS configuration, like `nh os switch` and its siblings
+pub fn switch(options: SwitchOptions) -> Result<SwitchOutcome> {
+    // Start from the defaults of the command line, as some of them only live in clap
+    let matches =
+        OsRebuildArgs::augment_args(clap::Command::new("nh-os")).try_get_matches_from(["nh-os"])?;
+    let mut args = OsRebuildArgs::from_arg_matches(&matches)?;
+
+    args.flakeref = Some(FlakeRef(options.flake));
+    args.hostname = options.hostname.map(Into::into);
+    args.specialisation = options.specialisation;
+    args.extra_args = options.extra_args;
+    args.common.dry = options.dry;
+    args.common.update = options.update;
+    args.common.no_preflight = options.no_preflight;
+    args.common.no_diff = options.no_diff;
+    args.common.nix_stderr = options.nix_stderr;
+    args.bypass_root_check = options.allow_root;
+    crate::util::configure_nix(options.nix_bin, None);
+
+    let action = match options.mode {
+        SwitchMode::Switch => OsRebuildType::Switch(args),
+        SwitchMode::Boot => OsRebuildType::Boot(args),
+        SwitchMode::Test => OsRebuildType::Test(args),
+        SwitchMode::Build => OsRebuildType::Build(args),
+    };
+    debug!(?action);
+
+    match &action {
+        OsRebuildType::Switch(args)
+        | OsRebuildType::Boot(args)
+        | OsRebuildType::Test(args)
+        | OsRebuildType::Build(args) => args.rebuild(&action),
+        _ => unreachable!(),
+    }
+}
+
+fn self_elevate() -> ! {
+    use std::os::unix::process::CommandExt;
+
+    let elevation = crate::elevate::current();
+    let [program, flags @ ..] = &elevation.args()[..] else {
+        unreachable!("The elevation program is always there");
+    };
+    let mut cmd = std::process::Command::new(program);
+    cmd.args(flags).args(std::env::args()).envs(elevation.env());
+    debug!("{:?}", cmd);
+    let err = cmd.exec();
+    panic!("{}", err);
+}
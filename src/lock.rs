@@ -0,0 +1,59 @@
+//! Serialization of activations
+//!
+//! The lock is an advisory `flock` on the directory containing the system profile. nh runs
+//! unprivileged, so it can't create a lockfile in the root-owned profiles directory, but it can
+//! open and lock the directory itself.
+
+use std::{fs::File, path::Path};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use tracing::{debug, info};
+
+/// Held for the duration of an activation, released when dropped
+#[derive(Debug)]
+pub struct ActivationLock {
+    _lock: Flock<File>,
+}
+
+impl ActivationLock {
+    /// Locks `dir`, either waiting for the current holder or failing right away
+    pub fn acquire(dir: &Path, wait: bool) -> Result<Self> {
+        let open = || File::open(dir).wrap_err_with(|| format!("Opening {dir:?} for locking"));
+
+        let lock = match Flock::lock(open()?, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((file, Errno::EWOULDBLOCK)) if wait => {
+                info!("Another activation is in progress, waiting for it to finish");
+                Flock::lock(file, FlockArg::LockExclusive)
+                    .map_err(|(_, errno)| errno)
+                    .wrap_err("Waiting for the activation lock")?
+            }
+            Err((_, Errno::EWOULDBLOCK)) => {
+                bail!("Another activation is in progress. Pass --wait to wait for it to finish")
+            }
+            Err((_, errno)) => {
+                return Err(errno).wrap_err_with(|| format!("Locking {dir:?}"));
+            }
+        };
+
+        debug!(?dir, "Acquired activation lock");
+        Ok(Self { _lock: lock })
+    }
+}
+
+#[test]
+fn test_activation_lock() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let first = ActivationLock::acquire(dir.path(), false).unwrap();
+    let err = ActivationLock::acquire(dir.path(), false).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Another activation is in progress"));
+
+    drop(first);
+    ActivationLock::acquire(dir.path(), false).unwrap();
+}
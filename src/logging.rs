@@ -1,10 +1,14 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::*;
 use owo_colors::OwoColorize;
 use tracing::Event;
 use tracing::Level;
+use tracing::Metadata;
 use tracing::Subscriber;
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::filter::FilterExt;
+use tracing_subscriber::filter::FilterFn;
 use tracing_subscriber::fmt;
 
 use tracing_subscriber::fmt::FormatEvent;
@@ -52,7 +56,24 @@ where
     }
 }
 
-pub(crate) fn setup_logging(verbose: bool) -> Result<()> {
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Whether nh should only print errors
+pub(crate) fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Filter for the human-readable layer
+fn info_filter(quiet: bool) -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+    filter_fn(move |meta| {
+        let level = *meta.level();
+        !quiet && ((level == Level::INFO) || (level == Level::WARN))
+    })
+}
+
+pub(crate) fn setup_logging(verbose: bool, quiet: bool) -> Result<()> {
+    QUIET.store(quiet, Ordering::Relaxed);
+
     color_eyre::config::HookBuilder::default()
         .display_location_section(true)
         .panic_section("Please report the bug at https://github.com/viperML/nh/issues")
@@ -65,7 +86,7 @@ pub(crate) fn setup_logging(verbose: bool) -> Result<()> {
         .compact()
         .with_line_number(true)
         .with_filter(EnvFilter::from_default_env().or(filter_fn(move |_| verbose)))
-        .with_filter(filter_fn(|meta| *meta.level() > Level::INFO));
+        .with_filter(filter_fn(move |meta| *meta.level() > Level::INFO && !quiet));
 
     let layer_info = fmt::layer()
         .with_writer(std::io::stderr)
@@ -73,10 +94,7 @@ pub(crate) fn setup_logging(verbose: bool) -> Result<()> {
         .with_target(false)
         .with_level(false)
         .event_format(InfoFormatter)
-        .with_filter(filter_fn(|meta| {
-            let level = *meta.level();
-            (level == Level::INFO) || (level == Level::WARN)
-        }));
+        .with_filter(info_filter(quiet));
 
     tracing_subscriber::registry()
         .with(layer_debug)
@@ -87,3 +105,40 @@ pub(crate) fn setup_logging(verbose: bool) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_quiet_filter() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::{Context, Layer};
+
+    struct Counter(Arc<AtomicUsize>);
+    impl<S: Subscriber> Layer<S> for Counter {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let count = |quiet: bool| {
+        let events = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry()
+            .with(Counter(events.clone()).with_filter(info_filter(quiet)));
+
+        tracing::subscriber::with_default(subscriber, || {
+            commands::CommandBuilder::default()
+                .args(["true"])
+                .message("Doing something")
+                .dry(true)
+                .build()
+                .unwrap()
+                .exec()
+                .unwrap();
+            tracing::warn!("Something looks off");
+        });
+
+        events.load(Ordering::Relaxed)
+    };
+
+    assert_eq!(count(false), 2);
+    assert_eq!(count(true), 0);
+}
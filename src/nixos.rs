@@ -1,47 +1,260 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::io::{IsTerminal, Write};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::{bail, Context};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat};
 use color_eyre::Result;
+use serde::Deserialize;
 
-use tracing::{debug, warn, info};
+use tracing::{debug, info, warn};
 
+use crate::config::Config;
+use crate::elevate;
+use crate::flake::FlakeCache;
+use crate::generations;
+use crate::hooks::Phase;
 use crate::interface::NHRunnable;
-use crate::interface::OsRebuildType::{self, Boot, Build, Switch, Test, Edit};
-use crate::interface::{self, OsRebuildArgs, FlakeRef, OsEditArgs};
-use crate::util::{compare_semver, get_nix_version};
+use crate::interface::OsRebuildType::{
+    self, Boot, Build, BuildOnly, Edit, Generations, Info, Sizes, Switch, Test,
+};
+use crate::interface::{
+    self, FlakeRef, OsBuildOnlyArgs, OsEditArgs, OsGenerationsArgs, OsRebuildArgs, OsSizesArgs,
+};
+use crate::lock::ActivationLock;
+use crate::state::State;
+use crate::systemd;
+use crate::tui;
 use crate::*;
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
+const BOOTED_PROFILE: &str = "/run/booted-system";
+
+/// Parts of a system that only take effect after a reboot
+const BOOT_FILES: &[&str] = &["kernel", "initrd", "kernel-modules"];
 
 const SPEC_LOCATION: &str = "/etc/specialisation";
 
+/// Out-link created by --keep-failed-result, relative to the working directory
+const FAILED_RESULT_LINK: &str = "result-failed";
+
+/// Result of a rebuild, from building up to activating the configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SwitchOutcome {
+    /// Store path of the built configuration
+    pub built_path: Option<PathBuf>,
+    /// Generation of the system profile before the rebuild
+    pub old_generation: Option<u32>,
+    /// Generation of the system profile after the rebuild, if it was changed
+    pub new_generation: Option<u32>,
+    /// Whether switch-to-configuration was run to activate the configuration
+    pub activated: bool,
+    /// Output of the diff provider
+    pub diff_summary: Option<String>,
+    /// Whether the previous configuration was restored, because --rollback-in wasn't confirmed
+    pub rolled_back: bool,
+}
+
 impl NHRunnable for interface::OsArgs {
     fn run(&self) -> Result<()> {
         match &self.action {
-            Switch(args) | Boot(args) | Test(args) | Build(args) => args.rebuild(&self.action),
+            Switch(args) | Boot(args) | Test(args) | Build(args) => {
+                let outcome = args.rebuild(&self.action)?;
+                debug!(?outcome);
+                Ok(())
+            }
+            BuildOnly(args) => {
+                let outcome = args.rebuild.rebuild(&self.action)?;
+                debug!(?outcome);
+                Ok(())
+            }
             Edit(args) => args.edit(),
-            s => bail!("Subcommand {:?} not yet implemented", s),
+            Generations(args) => args.list(),
+            Sizes(args) => args.compare(),
+            Info => system_info(),
         }
     }
 }
 
+/// Output of `nixos-version --json`
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NixosVersion {
+    #[serde(rename = "nixosVersion")]
+    label: String,
+    nixpkgs_revision: Option<String>,
+    configuration_revision: Option<String>,
+}
+
+/// Older versions of nixos-version ignore --json and only print the label
+fn parse_nixos_version(output: &str) -> NixosVersion {
+    serde_json::from_str(output).unwrap_or_else(|_| NixosVersion {
+        label: output.trim().to_string(),
+        nixpkgs_revision: None,
+        configuration_revision: None,
+    })
+}
+
+fn system_info() -> Result<()> {
+    let output = commands::CommandBuilder::default()
+        .args(["nixos-version", "--json"])
+        .build()?
+        .exec_capture();
+
+    let version = match output {
+        Ok(output) => parse_nixos_version(&output.unwrap_or_default()),
+        Err(err) => {
+            debug!("nixos-version --json failed: {err}");
+            let path = Path::new(CURRENT_PROFILE).join("nixos-version");
+            let label =
+                std::fs::read_to_string(&path).wrap_err_with(|| format!("Reading {path:?}"))?;
+            parse_nixos_version(&label)
+        }
+    };
+
+    let unknown = String::from("unknown");
+    println!("Label:                  {}", version.label);
+    println!(
+        "Nixpkgs revision:       {}",
+        version.nixpkgs_revision.as_ref().unwrap_or(&unknown)
+    );
+    println!(
+        "Configuration revision: {}",
+        version.configuration_revision.as_ref().unwrap_or(&unknown)
+    );
+
+    Ok(())
+}
+
 impl OsEditArgs {
     fn edit(&self) -> Result<()> {
-        commands::edit(self.flakeref.clone())
+        commands::edit(self.flakeref.clone(), self.dry)
+    }
+}
+
+impl OsGenerationsArgs {
+    fn list(&self) -> Result<()> {
+        use owo_colors::OwoColorize;
+
+        let state = State::load(&State::path_for(&self.profile)?)?;
+        let label = |generation: &generations::GenerationInfo| {
+            state
+                .profile(&self.profile)
+                .and_then(|profile| profile.label(generation.number, &generation.path))
+        };
+
+        if self.interactive {
+            let entries = generations::list(&self.profile)?
+                .into_iter()
+                .map(|generation| tui::Entry {
+                    label: label(&generation).map(String::from),
+                    generation,
+                })
+                .collect();
+            return self.browse(entries);
+        }
+
+        for generation in generations::list(&self.profile)? {
+            let date = humantime::format_rfc3339_seconds(generation.last_modified);
+            let number = generation.number.to_string();
+            let number = if generation.current {
+                format!("{} (current)", number.green().bold())
+            } else {
+                number
+            };
+
+            print!("{number}  {date}");
+            if let Some(description) = label(&generation) {
+                print!("  {description}");
+            }
+            println!();
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tui")]
+    fn browse(&self, entries: Vec<tui::Entry>) -> Result<()> {
+        match tui::run(tui::GenerationBrowser::new(entries))? {
+            tui::Action::Quit => Ok(()),
+            tui::Action::Diff(generation) => crate::diff::DiffTool::Nvd
+                .resolve()
+                .command(CURRENT_PROFILE, &generation.path)?
+                .exec(),
+            tui::Action::Rollback(generation) => {
+                switch_generation_command(&self.profile, generation.number)?.exec()?;
+                activation_command(&self.profile, "switch", "Activating configuration")?.exec()
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tui"))]
+    fn browse(&self, _entries: Vec<tui::Entry>) -> Result<()> {
+        bail!("--interactive needs nh to be built with the tui feature")
+    }
+}
+
+impl OsSizesArgs {
+    fn compare(&self) -> Result<()> {
+        let generations = generations::list(&self.profile)?;
+
+        let mut paths = Vec::new();
+        for number in &self.generations {
+            match generations.iter().find(|g| g.number == *number) {
+                Some(generation) if generation.path.exists() => {
+                    paths.push((*number, generation.path.clone()));
+                }
+                Some(generation) => warn!(
+                    "{} of generation {number} isn't in the store, skipping",
+                    generation.path.display()
+                ),
+                None => warn!("Generation {number} doesn't exist, skipping"),
+            }
+        }
+
+        if paths.is_empty() {
+            bail!("None of the generations could be found");
+        }
+
+        let output = commands::CommandBuilder::default()
+            .args([util::nix_bin(), "path-info", "--closure-size", "--json"])
+            .args(paths.iter().map(|(_, path)| path))
+            .build()?
+            .exec_capture()?
+            .context("Capturing nix path-info output")?;
+        let sizes = generations::parse_closure_sizes(&output)?;
+
+        let mut previous = None;
+        for (number, path) in &paths {
+            let Some(&size) = sizes.get(path) else {
+                warn!("{} isn't valid in the store, skipping", path.display());
+                continue;
+            };
+
+            print!("{number}  {}", util::format_size(size));
+            if let Some(previous) = previous {
+                print!("  {}", util::format_size_delta(previous, size));
+            }
+            println!();
+
+            previous = Some(size);
+        }
+
+        Ok(())
     }
 }
 
 impl OsRebuildArgs {
-    pub fn rebuild(&self, rebuild_type: &OsRebuildType) -> Result<()> {
-        if nix::unistd::Uid::effective().is_root() {
-            bail!("Don't run nh os as root. I will call sudo internally as needed");
+    pub fn rebuild(&self, rebuild_type: &OsRebuildType) -> Result<SwitchOutcome> {
+        if nix::unistd::Uid::effective().is_root() && !self.bypass_root_check {
+            bail!("Don't run nh os as root, or pass --bypass-root-check. I will call sudo internally as needed");
         }
 
-        let hostname = match &self.hostname {
-            Some(h) => h.to_owned(),
-            None => hostname::get().context("Failed to get hostname")?,
-        };
+        self.validate(rebuild_type)?;
 
         let out_dir = tempfile::Builder::new().prefix("nh-os-").tempdir()?;
         let out_link = out_dir.path().join("result");
@@ -49,34 +262,57 @@ impl OsRebuildArgs {
         debug!("out_dir: {:?}", out_dir);
         debug!("out_link {:?}", out_link);
 
-        let flakeref = self.flakeref.clone().or_else(|| {
-            warn!("NH_OS_FLAKE not set");
-            std::env::var("FLAKE").ok().map(|s| FlakeRef(s))
-        }).unwrap_or("./".into());
+        let flakeref = self
+            .flakeref
+            .clone()
+            .or_else(|| {
+                warn!("NH_OS_FLAKE not set");
+                std::env::var("FLAKE").ok().map(FlakeRef)
+            })
+            .unwrap_or("./".into());
 
-        let flake_output = format!(
-            "{}#nixosConfigurations.\"{:?}\".config.system.build.toplevel",
-            flakeref.deref(),
-            hostname
-        );
+        let flake_cache = FlakeCache::default();
+
+        let config = Config::load(&flakeref)?;
+        debug!(?config);
+
+        let hostname = match (&self.hostname, &config.hostname) {
+            (Some(h), _) => h.to_owned(),
+            (None, Some(h)) => h.into(),
+            (None, None) => hostname::get().context("Failed to get hostname")?,
+        };
+
+        let flakeref = self.build_flakeref(flakeref);
+        self.common.check_clean(&flakeref)?;
+
+        let phases = self.common.phases();
+        debug!(?phases);
+
+        if phases.preflight && !configuration_exists(&flake_cache, &flakeref, &hostname)? {
+            bail!(
+                "Configuration {:?} doesn't exist in {}",
+                hostname,
+                flakeref.deref()
+            );
+        }
+
+        let flake_output = toplevel_attr(&flakeref, &hostname);
 
         if self.common.update {
-            // Get the Nix version
-            let nix_version = get_nix_version().unwrap_or_else(|_| {
+            let nix_version = util::nix_version().unwrap_or_else(|_| {
                 panic!("Failed to get Nix version. Custom Nix fork?");
             });
 
             // Default interface for updating flake inputs
-            let mut update_args = vec!["nix", "flake", "update"];
+            let mut update_args = vec![util::nix_bin(), "flake", "update"];
 
-            // If user is on Nix 2.19.0 or above, --flake must be passed
-            if let Ok(ordering) = compare_semver(&nix_version, "2.19.0") {
-                if ordering == std::cmp::Ordering::Greater {
-                    update_args.push("--flake");
-                }
+            // Nix 2.19.0 and above expect the flake to be passed with --flake
+            if nix_version.flake_update_needs_flag() {
+                update_args.push("--flake");
             }
 
             update_args.push(&flakeref);
+            update_args.extend(self.common.nix_flags().iter().map(String::as_str));
 
             debug!("nix_version: {:?}", nix_version);
             debug!("update_args: {:?}", update_args);
@@ -88,14 +324,209 @@ impl OsRebuildArgs {
                 .exec()?;
         }
 
-        commands::BuildCommandBuilder::default()
+        let link_args: Vec<&OsStr> = match rebuild_type {
+            _ if self.common.builds_nothing() => vec![],
+            BuildOnly(OsBuildOnlyArgs {
+                out_link: Some(link),
+                ..
+            }) => vec!["--out-link".as_ref(), link.as_os_str()],
+            BuildOnly(_) => vec!["--no-link".as_ref()],
+            _ if phases.link => vec!["--out-link".as_ref(), out_link_str.as_ref()],
+            _ => vec!["--no-link".as_ref()],
+        };
+
+        let read_only_lock_file = self.common.read_only_lock_file(&flakeref);
+
+        self.common.hooks.run(Phase::PreBuild, None)?;
+
+        if !self.for_systems.is_empty() {
+            let builds = self.system_builds(
+                &flake_output,
+                out_dir.path(),
+                phases.link,
+                read_only_lock_file,
+                &config,
+            )?;
+            return build_for_systems(builds, &self.common.hooks);
+        }
+
+        let out_paths = self
+            .build_command(
+                &flake_output,
+                link_args,
+                self.common.system.clone(),
+                read_only_lock_file,
+                &config,
+            )?
+            .exec()?;
+
+        // The derivation or the plan was already printed, there is nothing to activate
+        if self.common.builds_nothing() {
+            return Ok(SwitchOutcome::default());
+        }
+
+        self.common
+            .hooks
+            .run(Phase::PostBuild, out_paths.first().map(PathBuf::as_path))?;
+
+        // Nothing but the path goes to stdout, so that it can be captured
+        if let BuildOnly(_) = rebuild_type {
+            commands::print_out_paths(std::io::stdout(), &out_paths)?;
+            return Ok(SwitchOutcome {
+                built_path: out_paths.first().cloned(),
+                ..Default::default()
+            });
+        }
+
+        let built = match (phases.link, out_paths.first()) {
+            (true, _) => out_link.clone(),
+            (false, Some(path)) => path.clone(),
+            (false, None) => bail!("nix build didn't report any output path"),
+        };
+
+        let outcome = self.activate(rebuild_type, &built, Path::new(SYSTEM_PROFILE))?;
+
+        // Drop the out dir *only* when we are finished
+        drop(out_dir);
+
+        Ok(outcome)
+    }
+
+    fn build_command<S: AsRef<std::ffi::OsStr>>(
+        &self,
+        flake_output: &str,
+        link_args: impl IntoIterator<Item = S>,
+        system: Option<String>,
+        read_only_lock_file: bool,
+        config: &Config,
+    ) -> Result<commands::BuildCommand> {
+        let (message, output_prefix) = match &system {
+            // The builds of --for run side by side, so every line says which one it's from
+            Some(system) if !self.for_systems.is_empty() => (
+                format!("Building NixOS configuration for {system}"),
+                Some(format!(
+                    "{}[{system}] ",
+                    crate::prefix::output_prefix().unwrap_or_default()
+                )),
+            ),
+            _ => (
+                String::from("Building NixOS configuration"),
+                crate::prefix::output_prefix(),
+            ),
+        };
+
+        Ok(commands::BuildCommandBuilder::default()
             .flakeref(flake_output)
-            .message("Building NixOS configuration")
-            .extra_args(["--out-link", out_link_str])
+            .message(message)
+            .output_prefix(output_prefix)
+            .extra_args(link_args)
+            .extra_args(config.extra_args())
+            .extra_args(self.common.nix_flags())
             .extra_args(&self.extra_args)
-            .nom(!self.common.no_nom)
-            .build()?
-            .exec()?;
+            .nom(self.common.use_nom(config)?)
+            .print_build_logs(self.common.print_build_logs)
+            .builders_use_substitutes(self.common.builders_use_substitutes)
+            .store(self.common.store.clone())
+            .system(system)
+            .build_report(self.common.build_report)
+            .trace_file(self.common.trace_file.clone())
+            .auto_features(self.common.auto_features)
+            .auto_recover(self.common.auto_recover)
+            .warning_summary(!self.common.no_warning_summary)
+            .eval_only(self.common.eval_only)
+            .report_hashes(self.common.report_hashes)
+            .progress_json(self.common.progress_json)
+            .stderr_policy(self.common.nix_stderr)
+            .plan(self.common.plan || self.common.plan_paths)
+            .plan_paths(self.common.plan_paths)
+            .recreate_lock_file(self.common.recreate_lock_file)
+            .refresh(self.common.refresh)
+            .read_only_lock_file(read_only_lock_file)
+            .build()?)
+    }
+
+    /// One build for each system of --for, linked to `result-<system>` in `out_dir`
+    fn system_builds(
+        &self,
+        flake_output: &str,
+        out_dir: &Path,
+        link: bool,
+        read_only_lock_file: bool,
+        config: &Config,
+    ) -> Result<Vec<(String, commands::BuildCommand)>> {
+        self.for_systems
+            .iter()
+            .map(|system| {
+                let out_link = out_dir.join(format!("result-{system}"));
+                let link_args: Vec<std::ffi::OsString> = if link {
+                    vec!["--out-link".into(), out_link.into()]
+                } else {
+                    vec!["--no-link".into()]
+                };
+
+                let cmd = self.build_command(
+                    flake_output,
+                    link_args,
+                    Some(system.clone()),
+                    read_only_lock_file,
+                    config,
+                )?;
+                Ok((system.clone(), cmd))
+            })
+            .collect()
+    }
+
+    fn validate(&self, rebuild_type: &OsRebuildType) -> Result<()> {
+        if self.common.store.is_some() && !matches!(rebuild_type, Build(_) | BuildOnly(_)) {
+            bail!("--store can only be used with nh os build, as the result can't be activated");
+        }
+
+        if self.reboot && !matches!(rebuild_type, Boot(_)) {
+            bail!("--reboot can only be used with nh os boot");
+        }
+
+        if !self.for_systems.is_empty() && !matches!(rebuild_type, Build(_)) {
+            bail!("--for can only be used with nh os build");
+        }
+
+        if !self.for_systems.is_empty() && self.common.builds_nothing() {
+            bail!("--for can't be combined with --eval-only or --plan");
+        }
+
+        if (self.no_restart || !self.skip_restart.is_empty())
+            && (!matches!(rebuild_type, Test(_) | Switch(_)) || self.activation_action.is_some())
+        {
+            bail!(
+                "--skip-restart and --no-restart can only be used with nh os test or nh os switch"
+            );
+        }
+
+        if (self.install_bootloader || self.no_bootloader)
+            && (!matches!(rebuild_type, Boot(_) | Switch(_)) || self.activation_action.is_some())
+        {
+            bail!("--install-bootloader and --no-bootloader can only be used with nh os switch or nh os boot");
+        }
+
+        if self.rollback_in.is_some() && !matches!(rebuild_type, Test(_) | Switch(_)) {
+            bail!("--rollback-in can only be used with nh os test or nh os switch");
+        }
+
+        Ok(())
+    }
+
+    /// Everything after the build: diffing, confirmation and activation of `out_link`
+    fn activate(
+        &self,
+        rebuild_type: &OsRebuildType,
+        out_link: &Path,
+        system_profile: &Path,
+    ) -> Result<SwitchOutcome> {
+        let mut outcome = SwitchOutcome {
+            built_path: Some(out_link.read_link().unwrap_or_else(|_| out_link.to_owned())),
+            old_generation: generations::current(system_profile),
+            ..Default::default()
+        };
+        debug!("built_path: {:?}", outcome.built_path);
 
         let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
 
@@ -114,69 +545,1048 @@ impl OsRebuildArgs {
 
         target_profile.try_exists().context("Doesn't exist")?;
 
-        commands::CommandBuilder::default()
-            .args(self.common.diff_provider.split_ascii_whitespace())
-            .args([
+        if self.common.phases().diff {
+            let to_terminal = std::io::stdout().is_terminal();
+            let diff = self.common.diff_tool.resolve().command_with_color(
                 CURRENT_PROFILE,
-                target_profile.to_str().unwrap(),
-            ])
-            .message("Comparing changes")
-            .build()?
-            .exec()?;
+                &target_profile,
+                to_terminal,
+            )?;
+
+            outcome.diff_summary = if logging::is_quiet() {
+                diff.exec_capture()?
+            } else {
+                // Shown as it comes, and kept for the outcome without the colours
+                let mut summary = String::new();
+                diff.exec_stream(|line| {
+                    println!("{line}");
+                    summary.push_str(&internal_json::strip_ansi(line));
+                    summary.push('\n');
+                    Ok(())
+                })?
+                .map(|_| summary)
+            };
+        }
+
+        // The running kernel is the booted one, which is older than the current system after a
+        // switch that didn't reboot
+        let running = Path::new(BOOTED_PROFILE);
+        let running = if running.exists() {
+            running
+        } else {
+            Path::new(CURRENT_PROFILE)
+        };
+        let changed = changed_boot_files(&boot_paths(running), &boot_paths(&target_profile));
+        if !changed.is_empty() {
+            warn!("⚠ reboot required: {} changed", changed.join(", "));
+        }
 
         if self.common.dry || matches!(rebuild_type, OsRebuildType::Build(_)) {
-            return Ok(());
+            return Ok(outcome);
         }
 
-        if self.common.ask {
+        if !self.force
+            && self.activation_action.is_none()
+            && is_up_to_date(
+                rebuild_type,
+                out_link,
+                &target_profile,
+                system_profile,
+                Path::new(CURRENT_PROFILE),
+            )
+        {
+            info!("System is already up to date, pass --force to activate it anyway");
+            return Ok(outcome);
+        }
+
+        if self.common.ask && !self.common.yes {
             info!("Apply the config?");
             let confirmation = dialoguer::Confirm::new().default(false).interact()?;
 
             if !confirmation {
-                return Ok(());
+                return Ok(outcome);
             }
         }
 
-        if let Test(_) | Switch(_) = rebuild_type {
-            // !! Use the target profile aka spec-namespaced
-            let switch_to_configuration =
-                target_profile.join("bin").join("switch-to-configuration");
-            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+        if let (true, Some(built_path)) = (self.verify, &outcome.built_path) {
+            verify_command(built_path)?.exec()?;
+        }
 
-            commands::CommandBuilder::default()
-                .args(["sudo", switch_to_configuration, "test"])
-                .message("Activating configuration")
-                .build()?
-                .exec()?;
+        let activation_lock = ActivationLock::acquire(system_profile.parent().unwrap(), self.wait)?;
+
+        self.common
+            .hooks
+            .run(Phase::PreActivate, outcome.built_path.as_deref())?;
+
+        // Resolve it now, as the activation repoints it to the new configuration
+        let previous_system = std::fs::canonicalize(CURRENT_PROFILE).ok();
+
+        let failed_before = if self.watch {
+            Some(systemd::failed_units()?)
+        } else {
+            None
+        };
+
+        let skipped = self.skipped_restarts(&target_profile)?;
+        if !skipped.is_empty() {
+            systemd::mask_command(&skipped, true)?.exec()?;
         }
 
-        if let Boot(_) | Switch(_) = rebuild_type {
-            commands::CommandBuilder::default()
-                .args([
-                    "sudo",
-                    "nix-env",
-                    "--profile",
-                    SYSTEM_PROFILE,
-                    "--set",
-                    out_link_str,
-                ])
-                .build()?
-                .exec()?;
+        let result = self.switch_to_configuration(
+            rebuild_type,
+            &target_profile,
+            out_link,
+            system_profile,
+            &skipped,
+            &mut outcome,
+        );
 
-            // !! Use the base profile aka no spec-namespace
-            let switch_to_configuration = out_link.join("bin").join("switch-to-configuration");
-            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+        // Failing to unmask must not hide how the activation went
+        if !skipped.is_empty() {
+            let unmask = systemd::mask_command(&skipped, false)?;
+            if let Err(err) = unmask.exec() {
+                warn!(
+                    "Failed to unmask the skipped units, they stay masked until a reboot or: {}\n{err}",
+                    commands::shell_join(&unmask.to_args())
+                );
+            }
+        }
 
-            commands::CommandBuilder::default()
-                .args(["sudo", switch_to_configuration, "boot"])
-                .message("Adding configuration to bootloader")
-                .build()?
-                .exec()?;
+        if let Err(err) = result {
+            return Err(match (self.keep_failed_result, &outcome.built_path) {
+                (true, Some(built_path)) => {
+                    keep_failed_result(err, built_path, Path::new(FAILED_RESULT_LINK), add_gc_root)
+                }
+                _ => err,
+            });
         }
 
-        // Drop the out dir *only* when we are finished
-        drop(out_dir);
+        if !skipped.is_empty() {
+            info!("{}", systemd::restart_report(&skipped));
+        }
+
+        if let (Some(seconds), true) = (self.rollback_in, outcome.activated) {
+            let Some(previous_system) = &previous_system else {
+                bail!("Couldn't resolve {CURRENT_PROFILE}, so there is nothing to roll back to");
+            };
+
+            if !confirm_within(&spawn_stdin_reader(), Duration::from_secs(seconds)) {
+                warn!("Configuration not confirmed, rolling back");
+                self.rollback(previous_system, system_profile, &outcome)?;
+                outcome.rolled_back = true;
+                return Ok(outcome);
+            }
+        }
+
+        drop(activation_lock);
+
+        self.common
+            .hooks
+            .run(Phase::PostActivate, outcome.built_path.as_deref())?;
+
+        if let Some(failed_before) = failed_before {
+            let failed_after = systemd::failed_units()?;
+            let new_failures = systemd::new_failures(&failed_before, &failed_after);
+
+            if !new_failures.is_empty() {
+                for unit in &new_failures {
+                    warn!("{unit} failed after the activation");
+                    systemd::journal_command(unit)?.exec()?;
+                }
+                bail!(
+                    "{} unit(s) started failing after the activation",
+                    new_failures.len()
+                );
+            }
+
+            info!("No new failed units");
+        }
+
+        if self.reboot {
+            if !self.common.yes {
+                info!("Reboot now?");
+                if !dialoguer::Confirm::new().default(false).interact()? {
+                    return Ok(outcome);
+                }
+            }
+
+            reboot_command()?.exec()?;
+        }
+
+        Ok(outcome)
+    }
+
+    /// Runs switch-to-configuration and updates the system profile as needed by `rebuild_type`
+    fn switch_to_configuration(
+        &self,
+        rebuild_type: &OsRebuildType,
+        target_profile: &Path,
+        out_link: &Path,
+        system_profile: &Path,
+        skipped: &BTreeSet<String>,
+        outcome: &mut SwitchOutcome,
+    ) -> Result<()> {
+        let out_link_str = out_link.to_str().unwrap();
+        let system_profile_str = system_profile.to_str().unwrap();
+
+        if let Some(action) = &self.activation_action {
+            warn!("Passing unvalidated action {action:?} to switch-to-configuration");
+            activation_command(target_profile, action, "Running activation action")?.exec()?;
+            outcome.activated = true;
+        } else {
+            if let Test(_) | Switch(_) = rebuild_type {
+                // !! Use the target profile aka spec-namespaced
+                let result =
+                    activation_command(target_profile, "test", "Activating configuration")?.exec();
+
+                match result {
+                    // switch-to-configuration exits with 4 when units fail to restart, which
+                    // masked units always do
+                    Err(err)
+                        if !skipped.is_empty() && commands::ExitError::code_of(&err) == Some(4) =>
+                    {
+                        warn!("Some units couldn't be restarted, including the skipped ones");
+                    }
+                    result => result?,
+                }
+                outcome.activated = true;
+            }
+
+            if let Boot(_) | Switch(_) = rebuild_type {
+                elevate::command()
+                    .args([
+                        "nix-env",
+                        "--profile",
+                        system_profile_str,
+                        "--set",
+                        out_link_str,
+                    ])
+                    .build()?
+                    .exec()?;
+
+                if let Some(cmd) = self.bootloader_command(out_link)? {
+                    cmd.exec()?;
+                }
+
+                outcome.new_generation = generations::current(system_profile);
+
+                if let (Some(message), Some(number), Some(built_path)) =
+                    (&self.message, outcome.new_generation, &outcome.built_path)
+                {
+                    let path = State::path_for(system_profile)?;
+                    let mut state = State::load(&path)?;
+                    state
+                        .profile_mut(system_profile)
+                        .set_label(number, built_path, message);
+                    state.save(&path)?;
+                }
+            }
+        }
 
         Ok(())
     }
+
+    /// The flake to build, which --override-flake swaps for the one the host and config files
+    /// come from
+    fn build_flakeref(&self, flakeref: FlakeRef) -> FlakeRef {
+        match &self.override_flake {
+            Some(flake) => {
+                info!("Building {} instead of {}", flake.deref(), flakeref.deref());
+                flake.clone()
+            }
+            None => flakeref,
+        }
+    }
+
+    /// Adds the configuration to the bootloader, unless --no-bootloader is set
+    fn bootloader_command(&self, out_link: &Path) -> Result<Option<commands::Command>> {
+        if self.no_bootloader {
+            info!("Not touching the bootloader, as --no-bootloader is set");
+            return Ok(None);
+        }
+
+        if !self.install_bootloader {
+            // !! Use the base profile aka no spec-namespace
+            return Ok(Some(activation_command(
+                out_link,
+                "boot",
+                "Adding configuration to bootloader",
+            )?));
+        }
+
+        // Elevation resets the environment, so the variable goes through env
+        let switch_to_configuration = out_link.join("bin").join("switch-to-configuration");
+        Ok(Some(
+            elevate::command()
+                .args(["env", "NIXOS_INSTALL_BOOTLOADER=1"])
+                .args([switch_to_configuration.as_os_str(), OsStr::new("boot")])
+                .message("Installing the bootloader")
+                .build()?,
+        ))
+    }
+
+    /// Units of --skip-restart or --no-restart that the activation of `target_profile` would
+    /// restart
+    fn skipped_restarts(&self, target_profile: &Path) -> Result<BTreeSet<String>> {
+        if !self.no_restart && self.skip_restart.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+
+        let mut would_restart = systemd::would_restart(target_profile)?;
+        if !self.no_restart {
+            would_restart.retain(|unit| self.skip_restart.contains(unit));
+        }
+        Ok(would_restart)
+    }
+
+    /// Restores `previous_system`, undoing the profile change if there was one
+    fn rollback(
+        &self,
+        previous_system: &Path,
+        system_profile: &Path,
+        outcome: &SwitchOutcome,
+    ) -> Result<()> {
+        let action = match (outcome.new_generation, outcome.old_generation) {
+            (Some(_), Some(old_generation)) => {
+                switch_generation_command(system_profile, old_generation)?.exec()?;
+                "switch"
+            }
+            _ => "test",
+        };
+
+        activation_command(
+            previous_system,
+            action,
+            "Restoring the previous configuration",
+        )?
+        .exec()
+    }
+}
+
+/// Whether activating would change nothing, as the profiles touched by `rebuild_type` already
+/// point to the built configuration
+fn is_up_to_date(
+    rebuild_type: &OsRebuildType,
+    built: &Path,
+    target_profile: &Path,
+    system_profile: &Path,
+    current_profile: &Path,
+) -> bool {
+    let same = |a: &Path, b: &Path| match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+
+    match rebuild_type {
+        Test(_) => same(target_profile, current_profile),
+        Boot(_) => same(built, system_profile),
+        Switch(_) => same(target_profile, current_profile) && same(built, system_profile),
+        _ => false,
+    }
+}
+
+/// Runs the builds side by side, reporting the result of each system at the end
+///
+/// They can't share a single nix invocation, as each needs its own --system.
+fn build_for_systems(
+    builds: Vec<(String, commands::BuildCommand)>,
+    hooks: &interface::HookArgs,
+) -> Result<SwitchOutcome> {
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = builds
+            .into_iter()
+            .map(|(system, cmd)| {
+                let handle = scope.spawn(move || {
+                    let out_paths = cmd.exec()?;
+                    hooks.run(Phase::PostBuild, out_paths.first().map(PathBuf::as_path))?;
+                    Ok(out_paths)
+                });
+                (system, handle)
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|(system, handle)| {
+                let result: Result<Vec<PathBuf>> = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(eyre!("The build panicked")));
+                (system, result)
+            })
+            .collect()
+    });
+
+    let mut failures = 0;
+    for (system, result) in &results {
+        match result {
+            Ok(out_paths) => match out_paths.first() {
+                Some(path) => info!("{system}: {}", path.display()),
+                None => info!("{system}: built"),
+            },
+            Err(err) => {
+                failures += 1;
+                warn!("{system}: {err:?}");
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{failures} of {} system(s) failed to build", results.len());
+    }
+
+    Ok(SwitchOutcome::default())
+}
+
+/// Reads a single line from stdin in a background thread
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line).is_ok() {
+            let _ = tx.send(line);
+        }
+    });
+    rx
+}
+
+/// Asks to keep the new configuration, counting down from `window`
+///
+/// Anything but a yes, including running out of time, means rolling back.
+fn confirm_within(answers: &Receiver<String>, window: Duration) -> bool {
+    let deadline = Instant::now() + window;
+
+    let answer = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break Err(RecvTimeoutError::Timeout);
+        }
+
+        eprint!(
+            "\rKeep this configuration? [y/N] (rolling back in {}s) ",
+            remaining.as_secs_f64().ceil()
+        );
+        let _ = std::io::stderr().flush();
+
+        match answers.recv_timeout(remaining.min(Duration::from_secs(1))) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            answer => break answer,
+        }
+    };
+    eprintln!();
+
+    matches!(
+        answer.as_deref().map(str::trim),
+        Ok("y" | "Y" | "yes" | "Yes")
+    )
+}
+
+/// Calls `switch-to-configuration` from the given system closure with `action`
+fn activation_command(profile: &Path, action: &str, message: &str) -> Result<commands::Command> {
+    let switch_to_configuration = profile.join("bin").join("switch-to-configuration");
+
+    Ok(elevate::command()
+        .args([switch_to_configuration.to_str().unwrap(), action])
+        .message(message)
+        .build()?)
+}
+
+/// Links `store_path` to `link` with `add_root` after a failed activation, mentioning it in `err`
+fn keep_failed_result<F>(
+    err: color_eyre::Report,
+    store_path: &Path,
+    link: &Path,
+    add_root: F,
+) -> color_eyre::Report
+where
+    F: FnOnce(&Path, &Path) -> Result<()>,
+{
+    match add_root(store_path, link) {
+        Ok(()) => {
+            let link = std::path::absolute(link).unwrap_or_else(|_| link.to_owned());
+            err.wrap_err(format!(
+                "Activation failed, the configuration was kept at {}",
+                link.display()
+            ))
+        }
+        Err(link_err) => {
+            warn!(
+                "Couldn't keep the configuration at {}: {link_err}",
+                link.display()
+            );
+            err
+        }
+    }
+}
+
+/// Installable of the system closure of `hostname` in `flakeref`
+fn toplevel_attr(flakeref: &FlakeRef, hostname: &OsStr) -> String {
+    format!(
+        "{}#nixosConfigurations.\"{:?}\".config.system.build.toplevel",
+        flakeref.deref(),
+        hostname
+    )
+}
+
+fn configuration_exists(
+    flake_cache: &FlakeCache,
+    flakeref: &FlakeRef,
+    hostname: &OsStr,
+) -> Result<bool> {
+    flake_cache.has_attr(flakeref, "nixosConfigurations", &hostname.to_string_lossy())
+}
+
+/// Store paths of the [`BOOT_FILES`] of `system`, for the ones it has
+fn boot_paths(system: &Path) -> BTreeMap<&'static str, PathBuf> {
+    BOOT_FILES
+        .iter()
+        .filter_map(|&name| Some((name, std::fs::canonicalize(system.join(name)).ok()?)))
+        .collect()
+}
+
+/// Boot files that differ between the `old` and `new` systems
+///
+/// A file missing on either side, like the kernel of a container, isn't a reason to reboot.
+fn changed_boot_files(
+    old: &BTreeMap<&'static str, PathBuf>,
+    new: &BTreeMap<&'static str, PathBuf>,
+) -> Vec<&'static str> {
+    BOOT_FILES
+        .iter()
+        .copied()
+        .filter(|name| match (old.get(name), new.get(name)) {
+            (Some(old), Some(new)) => old != new,
+            _ => false,
+        })
+        .collect()
+}
+
+/// Registers `link` as a garbage collector root for `store_path`
+fn add_gc_root(store_path: &Path, link: &Path) -> Result<()> {
+    commands::CommandBuilder::default()
+        .args(["nix-store", "--add-root"])
+        .args([link])
+        .args(["--realise"])
+        .args([store_path])
+        .build()?
+        .exec_capture()?;
+    Ok(())
+}
+
+/// Points `profile` back to an existing generation
+fn switch_generation_command(profile: &Path, number: u32) -> Result<commands::Command> {
+    Ok(elevate::command()
+        .args(["nix-env", "--profile"])
+        .args([profile])
+        .args(["--switch-generation", &number.to_string()])
+        .message(format!("Switching to generation {number}"))
+        .build()?)
+}
+
+/// Checks the signatures of the whole closure, without hashing the contents
+fn verify_command(path: &Path) -> Result<commands::Command> {
+    Ok(commands::CommandBuilder::default()
+        .args([
+            util::nix_bin(),
+            "store",
+            "verify",
+            "--no-contents",
+            "--recursive",
+        ])
+        .args([path])
+        .message("Verifying the built configuration")
+        .build()?)
+}
+
+fn reboot_command() -> Result<commands::Command> {
+    Ok(elevate::command()
+        .args(["systemctl", "reboot"])
+        .message("Rebooting")
+        .build()?)
+}
+
+/// Parses `nh os <argv>`
+#[cfg(test)]
+fn os_args(argv: &[&str]) -> interface::OsArgs {
+    use crate::interface::{NHCommand, NHParser};
+    use clap::Parser;
+
+    let parsed = NHParser::parse_from(["nh", "os"].iter().chain(argv));
+    let NHCommand::Os(os_args) = parsed.command else {
+        panic!("Expected nh os");
+    };
+    os_args
+}
+
+#[cfg(test)]
+fn rebuild_args(action: &OsRebuildType) -> &OsRebuildArgs {
+    match action {
+        Switch(args) | Boot(args) | Test(args) | Build(args) => args,
+        BuildOnly(args) => &args.rebuild,
+        _ => panic!("Expected a rebuild"),
+    }
+}
+
+#[test]
+fn test_activation_command_raw_action() {
+    let cmd = activation_command(
+        Path::new("/nix/store/foo-nixos-system"),
+        "some-future-action",
+        "Running activation action",
+    )
+    .unwrap();
+
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "sudo",
+            "/nix/store/foo-nixos-system/bin/switch-to-configuration",
+            "some-future-action",
+        ]
+    );
+}
+
+#[test]
+fn test_switch_outcome_dry() {
+    let tmp = tempfile::tempdir().unwrap();
+    let built = tmp.path().join("nixos-system");
+    std::fs::create_dir(&built).unwrap();
+    let out_link = tmp.path().join("result");
+    std::os::unix::fs::symlink(&built, &out_link).unwrap();
+    let system_profile = tmp.path().join("system");
+    std::os::unix::fs::symlink("system-42-link", &system_profile).unwrap();
+
+    let parsed = os_args(&["switch", "--dry", "-D", "echo", "/flake"]);
+    let outcome = rebuild_args(&parsed.action)
+        .activate(&parsed.action, &out_link, &system_profile)
+        .unwrap();
+
+    assert_eq!(
+        outcome,
+        SwitchOutcome {
+            built_path: Some(built.clone()),
+            old_generation: Some(42),
+            new_generation: None,
+            activated: false,
+            diff_summary: Some(format!("{} {}\n", CURRENT_PROFILE, out_link.display())),
+            rolled_back: false,
+        }
+    );
+}
+
+#[test]
+fn test_verify_command() {
+    assert_eq!(
+        verify_command(Path::new("/nix/store/abc-nixos-system"))
+            .unwrap()
+            .to_args(),
+        [
+            "nix",
+            "store",
+            "verify",
+            "--no-contents",
+            "--recursive",
+            "/nix/store/abc-nixos-system"
+        ]
+    );
+}
+
+#[test]
+fn test_reboot() {
+    assert_eq!(
+        reboot_command().unwrap().to_args(),
+        ["sudo", "systemctl", "reboot"]
+    );
+
+    for (action, valid) in [
+        ("boot", true),
+        ("switch", false),
+        ("test", false),
+        ("build", false),
+    ] {
+        let parsed = os_args(&[action, "--reboot"]);
+        let args = rebuild_args(&parsed.action);
+        assert_eq!(args.validate(&parsed.action).is_ok(), valid, "{action}");
+    }
+}
+
+#[test]
+fn test_rollback_confirmation() {
+    let window = Duration::from_millis(50);
+
+    // Nobody answers
+    let (_tx, rx) = mpsc::channel::<String>();
+    assert!(!confirm_within(&rx, window));
+
+    // stdin was closed
+    let (tx, rx) = mpsc::channel::<String>();
+    drop(tx);
+    assert!(!confirm_within(&rx, window));
+
+    for (answer, keep) in [
+        ("y\n", true),
+        ("yes\n", true),
+        ("n\n", false),
+        ("\n", false),
+    ] {
+        let (tx, rx) = mpsc::channel();
+        tx.send(answer.to_string()).unwrap();
+        assert_eq!(confirm_within(&rx, window), keep, "{answer:?}");
+    }
+}
+
+#[test]
+fn test_system_builds() {
+    let parsed = os_args(&[
+        "build",
+        "--nom",
+        "never",
+        "--for",
+        "x86_64-linux,aarch64-linux",
+        "/flake",
+    ]);
+    let args = rebuild_args(&parsed.action);
+    args.validate(&parsed.action).unwrap();
+
+    let flake_output = r#"/flake#nixosConfigurations."host".config.system.build.toplevel"#;
+    let builds = args
+        .system_builds(
+            flake_output,
+            Path::new("/tmp/nh-os"),
+            true,
+            false,
+            &Config::default(),
+        )
+        .unwrap();
+
+    let builds: Vec<_> = builds
+        .iter()
+        .map(|(system, cmd)| (system.as_str(), cmd.output_prefix(), cmd.to_args()))
+        .collect();
+    assert_eq!(builds.len(), 2);
+    for ((system, prefix, args), expected) in builds.iter().zip(["x86_64-linux", "aarch64-linux"]) {
+        assert_eq!(*system, expected);
+        // The builds run side by side, so their logs are told apart by system
+        assert_eq!(*prefix, Some(format!("[{expected}] ").as_str()));
+        assert_eq!(
+            *args,
+            [
+                "nix",
+                "build",
+                flake_output,
+                "--print-out-paths",
+                "--system",
+                expected,
+                "--out-link",
+                &format!("/tmp/nh-os/result-{expected}"),
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_is_up_to_date() {
+    use std::os::unix::fs::symlink;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let old = tmp.path().join("old-nixos-system");
+    let new = tmp.path().join("new-nixos-system");
+    std::fs::create_dir(&old).unwrap();
+    std::fs::create_dir(&new).unwrap();
+
+    let out_link = tmp.path().join("result");
+    symlink(&new, &out_link).unwrap();
+    let system_profile = tmp.path().join("system");
+    symlink(&old, &system_profile).unwrap();
+    let current_profile = tmp.path().join("current-system");
+    symlink(&new, &current_profile).unwrap();
+
+    let up_to_date = |action: &str| {
+        is_up_to_date(
+            &os_args(&[action, "/flake"]).action,
+            &out_link,
+            &out_link,
+            &system_profile,
+            &current_profile,
+        )
+    };
+
+    // Only the running system was updated, like after nh os test
+    assert!(up_to_date("test"));
+    assert!(!up_to_date("boot"));
+    assert!(!up_to_date("switch"));
+
+    std::fs::remove_file(&system_profile).unwrap();
+    symlink(&new, &system_profile).unwrap();
+    assert!(up_to_date("boot"));
+    assert!(up_to_date("switch"));
+
+    // Nothing can be compared against
+    std::fs::remove_file(&current_profile).unwrap();
+    assert!(!up_to_date("switch"));
+}
+
+#[test]
+fn test_nix_flags_before_extra_args() {
+    use crate::interface::NHParser;
+    use clap::Parser;
+
+    let mut parsed = os_args(&[
+        "build",
+        "--no-nom",
+        "--nix-flags",
+        "--option narinfo-cache-negative-ttl 0 --option extra-substituters 'https://a https://b'",
+        "/flake",
+    ]);
+    let Build(args) = &mut parsed.action else {
+        panic!("Expected nh os build");
+    };
+    args.extra_args.push(String::from("--impure"));
+
+    let cmd = args
+        .build_command("/flake#foo", ["--no-link"], None, false, &Config::default())
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        [
+            "nix",
+            "build",
+            "/flake#foo",
+            "--print-out-paths",
+            "--no-link",
+            "--option",
+            "narinfo-cache-negative-ttl",
+            "0",
+            "--option",
+            "extra-substituters",
+            "https://a https://b",
+            "--impure",
+        ]
+    );
+
+    assert!(NHParser::try_parse_from([
+        "nh",
+        "os",
+        "build",
+        "--nix-flags",
+        "'unbalanced",
+        "/flake"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_keep_failed_result() {
+    let tmp = tempfile::tempdir().unwrap();
+    let built = tmp.path().join("nixos-system");
+    std::fs::create_dir(&built).unwrap();
+    let link = tmp.path().join("result-failed");
+
+    let activation_err = color_eyre::eyre::eyre!("switch-to-configuration failed");
+    let err = keep_failed_result(activation_err, &built, &link, |store_path, link| {
+        Ok(std::os::unix::fs::symlink(store_path, link)?)
+    });
+
+    assert_eq!(link.read_link().unwrap(), built);
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Activation failed, the configuration was kept at {}",
+            link.display()
+        )
+    );
+    assert!(err
+        .chain()
+        .any(|cause| cause.to_string() == "switch-to-configuration failed"));
+
+    // The original error is kept as is if the link can't be created
+    let err = keep_failed_result(
+        color_eyre::eyre::eyre!("switch-to-configuration failed"),
+        &built,
+        &link,
+        |_, _| color_eyre::eyre::bail!("no permission"),
+    );
+    assert_eq!(err.to_string(), "switch-to-configuration failed");
+}
+
+#[test]
+fn test_parse_nixos_version() {
+    let version = parse_nixos_version(
+        r#"{"configurationRevision":"6f0a7d3c9e1b","nixosVersion":"24.05.20240612.a1b2c3d","nixpkgsRevision":"a1b2c3d4e5f60718293a4b5c6d7e8f9012345678"}"#,
+    );
+    assert_eq!(
+        version,
+        NixosVersion {
+            label: String::from("24.05.20240612.a1b2c3d"),
+            nixpkgs_revision: Some(String::from("a1b2c3d4e5f60718293a4b5c6d7e8f9012345678")),
+            configuration_revision: Some(String::from("6f0a7d3c9e1b")),
+        }
+    );
+
+    // Systems built without a flake don't record the configuration revision
+    let version = parse_nixos_version(r#"{"nixosVersion":"24.05.20240612.a1b2c3d"}"#);
+    assert_eq!(version.configuration_revision, None);
+
+    // Older systems only print the label
+    let version = parse_nixos_version("23.05.4567.deadbeef (Stoat)\n");
+    assert_eq!(version.label, "23.05.4567.deadbeef (Stoat)");
+    assert_eq!(version.nixpkgs_revision, None);
+}
+
+#[test]
+fn test_skip_restart_args() {
+    let parsed = os_args(&["switch", "--skip-restart", "nginx,backup.timer", "/flake"]);
+    let args = rebuild_args(&parsed.action);
+    args.validate(&parsed.action).unwrap();
+    assert_eq!(args.skip_restart, ["nginx.service", "backup.timer"]);
+
+    let parsed = os_args(&["boot", "--no-restart", "/flake"]);
+    assert!(rebuild_args(&parsed.action)
+        .validate(&parsed.action)
+        .is_err());
+}
+
+#[test]
+fn test_changed_boot_files() {
+    let system = |kernel: &str, initrd: &str, modules: &str| {
+        BTreeMap::from([
+            (
+                "kernel",
+                PathBuf::from(format!("/nix/store/{kernel}-linux/bzImage")),
+            ),
+            (
+                "initrd",
+                PathBuf::from(format!("/nix/store/{initrd}-initrd/initrd")),
+            ),
+            (
+                "kernel-modules",
+                PathBuf::from(format!("/nix/store/{modules}-modules")),
+            ),
+        ])
+    };
+
+    let old = system("aaa", "bbb", "ccc");
+    assert!(changed_boot_files(&old, &old.clone()).is_empty());
+    assert_eq!(
+        changed_boot_files(&old, &system("aaa", "ddd", "ccc")),
+        ["initrd"]
+    );
+    assert_eq!(
+        changed_boot_files(&old, &system("eee", "ddd", "fff")),
+        ["kernel", "initrd", "kernel-modules"]
+    );
+
+    // Containers have no kernel
+    assert!(changed_boot_files(&old, &BTreeMap::new()).is_empty());
+    assert!(changed_boot_files(&BTreeMap::new(), &old).is_empty());
+
+    let tmp = tempfile::tempdir().unwrap();
+    let kernel = tmp.path().join("bzImage");
+    std::fs::write(&kernel, "").unwrap();
+    let toplevel = tmp.path().join("system");
+    std::fs::create_dir(&toplevel).unwrap();
+    std::os::unix::fs::symlink(&kernel, toplevel.join("kernel")).unwrap();
+    assert_eq!(
+        boot_paths(&toplevel),
+        BTreeMap::from([("kernel", std::fs::canonicalize(&kernel).unwrap())])
+    );
+}
+
+#[test]
+fn test_bootloader_args() {
+    use crate::interface::NHParser;
+    use clap::Parser;
+
+    let out_link = Path::new("/tmp/result");
+
+    let parsed = os_args(&["switch", "/flake"]);
+    let cmd = rebuild_args(&parsed.action)
+        .bootloader_command(out_link)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        cmd.to_args(),
+        ["sudo", "/tmp/result/bin/switch-to-configuration", "boot"]
+    );
+
+    let parsed = os_args(&["boot", "--install-bootloader", "/flake"]);
+    let args = rebuild_args(&parsed.action);
+    args.validate(&parsed.action).unwrap();
+    assert_eq!(
+        args.bootloader_command(out_link)
+            .unwrap()
+            .unwrap()
+            .to_args(),
+        [
+            "sudo",
+            "env",
+            "NIXOS_INSTALL_BOOTLOADER=1",
+            "/tmp/result/bin/switch-to-configuration",
+            "boot"
+        ]
+    );
+
+    let parsed = os_args(&["switch", "--no-bootloader", "/flake"]);
+    let args = rebuild_args(&parsed.action);
+    args.validate(&parsed.action).unwrap();
+    assert!(args.bootloader_command(out_link).unwrap().is_none());
+
+    // Both at once make no sense
+    assert!(NHParser::try_parse_from([
+        "nh",
+        "os",
+        "switch",
+        "--no-bootloader",
+        "--install-bootloader",
+        "/flake"
+    ])
+    .is_err());
+
+    // There is no bootloader step to skip
+    let parsed = os_args(&["test", "--no-bootloader", "/flake"]);
+    assert!(rebuild_args(&parsed.action)
+        .validate(&parsed.action)
+        .is_err());
+}
+
+#[test]
+fn test_override_flake() {
+    use crate::interface::NHParser;
+    use clap::Parser;
+
+    let parsed = os_args(&[
+        "build",
+        "--override-flake",
+        "github:me/alt-config",
+        "/etc/nixos",
+    ]);
+    let args = rebuild_args(&parsed.action);
+
+    // The override replaces the flake, the attribute still comes from the host
+    let flakeref = args.build_flakeref(args.flakeref.clone().unwrap());
+    let attr = toplevel_attr(&flakeref, OsStr::new("laptop"));
+    assert_eq!(
+        attr,
+        toplevel_attr(
+            &FlakeRef::from("github:me/alt-config"),
+            OsStr::new("laptop")
+        )
+    );
+    assert!(attr.starts_with("github:me/alt-config#nixosConfigurations."));
+    assert!(attr.contains("laptop"));
+
+    // Without it, the usual flake is built
+    let parsed = os_args(&["build", "/etc/nixos"]);
+    let args = rebuild_args(&parsed.action);
+    assert_eq!(
+        args.build_flakeref(args.flakeref.clone().unwrap()).deref(),
+        "/etc/nixos"
+    );
+
+    // The attribute comes from the host, so the override can't name one
+    for bad in ["github:me/alt#other", "", "my flake"] {
+        assert!(
+            NHParser::try_parse_from(["nh", "os", "build", "--override-flake", bad]).is_err(),
+            "{bad:?}"
+        );
+    }
 }
@@ -0,0 +1,220 @@
+//! Notification when nh finishes, on the desktop or through ntfy
+
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use tracing::{debug, warn};
+
+use crate::commands::CommandBuilder;
+use crate::util;
+
+/// Server of the ntfy topics given by name only
+const NTFY_SERVER: &str = "https://ntfy.sh";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+    pub success: bool,
+}
+
+impl Notification {
+    /// Notification of `command` having finished after `elapsed`, with the error if it failed
+    pub fn finished(command: &str, result: &Result<()>, elapsed: Duration) -> Self {
+        // Whole seconds are enough for a build, and read better
+        let elapsed = humantime::format_duration(Duration::from_secs(elapsed.as_secs()));
+        match result {
+            Ok(()) => Self {
+                title: String::from("nh succeeded"),
+                body: format!("{command} finished after {elapsed}"),
+                success: true,
+            },
+            Err(err) => Self {
+                title: String::from("nh failed"),
+                body: format!("{command} failed after {elapsed}: {err}"),
+                success: false,
+            },
+        }
+    }
+}
+
+/// Where notifications go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notifier {
+    /// notify-send, for a graphical session
+    Desktop,
+    /// URL of an ntfy topic
+    Ntfy(String),
+}
+
+/// Request posting a notification to ntfy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtfyRequest {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Notifier {
+    /// notify-send if there's a graphical session to show it in, else the ntfy topic of the
+    /// config file
+    pub fn detect(ntfy_topic: Option<&str>) -> Option<Self> {
+        let graphical = ["DISPLAY", "WAYLAND_DISPLAY"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()));
+        if graphical && util::in_path("notify-send") {
+            return Some(Self::Desktop);
+        }
+        ntfy_topic.map(|topic| Self::Ntfy(ntfy_url(topic)))
+    }
+
+    pub fn send(&self, notification: &Notification) -> Result<()> {
+        match self {
+            Self::Desktop => {
+                CommandBuilder::default()
+                    .args(notify_send_args(notification))
+                    .build()?
+                    .exec()?;
+            }
+            Self::Ntfy(url) => {
+                let request = ntfy_request(url, notification);
+                let mut builder = reqwest::blocking::Client::new()
+                    .post(&request.url)
+                    .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
+                    .timeout(Duration::from_secs(10))
+                    .body(request.body);
+                for (name, value) in request.headers {
+                    builder = builder.header(name, value);
+                }
+                let response = builder.send().wrap_err("Posting to ntfy")?;
+                if !response.status().is_success() {
+                    bail!("ntfy answered {}", response.status());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// URL of an ntfy topic, which can be given by name for the public server
+fn ntfy_url(topic: &str) -> String {
+    if topic.contains("://") {
+        topic.to_string()
+    } else {
+        format!("{NTFY_SERVER}/{}", topic.trim_start_matches('/'))
+    }
+}
+
+/// Command line of notify-send showing `notification`
+fn notify_send_args(notification: &Notification) -> Vec<String> {
+    let urgency = if notification.success {
+        "normal"
+    } else {
+        "critical"
+    };
+    vec![
+        String::from("notify-send"),
+        String::from("--app-name=nh"),
+        format!("--urgency={urgency}"),
+        notification.title.clone(),
+        notification.body.clone(),
+    ]
+}
+
+/// Post of `notification` to the ntfy topic at `url`, with the title and priority as headers
+fn ntfy_request(url: &str, notification: &Notification) -> NtfyRequest {
+    let (tags, priority) = if notification.success {
+        ("white_check_mark", "default")
+    } else {
+        ("x", "high")
+    };
+    NtfyRequest {
+        url: url.to_string(),
+        headers: vec![
+            ("Title", notification.title.clone()),
+            ("Tags", String::from(tags)),
+            ("Priority", String::from(priority)),
+        ],
+        body: notification.body.clone(),
+    }
+}
+
+/// Sends `notification`, only warning if that fails, as it must never fail the command
+pub fn notify(ntfy_topic: Option<&str>, notification: &Notification) {
+    let Some(notifier) = Notifier::detect(ntfy_topic) else {
+        warn!("Can't notify: notify-send isn't available and no ntfy-topic is configured");
+        return;
+    };
+    debug!(?notifier, ?notification);
+
+    if let Err(err) = notifier.send(notification) {
+        warn!("Failed to send the notification: {err}");
+    }
+}
+
+#[test]
+fn test_notify_send_args() {
+    let success = Notification::finished("nh os switch", &Ok(()), Duration::from_millis(125_400));
+    assert_eq!(
+        notify_send_args(&success),
+        [
+            "notify-send",
+            "--app-name=nh",
+            "--urgency=normal",
+            "nh succeeded",
+            "nh os switch finished after 2m 5s",
+        ]
+    );
+
+    let failure = Notification::finished(
+        "nh home switch",
+        &Err(color_eyre::eyre::eyre!("Building the configuration")),
+        Duration::from_secs(3),
+    );
+    assert_eq!(
+        notify_send_args(&failure),
+        [
+            "notify-send",
+            "--app-name=nh",
+            "--urgency=critical",
+            "nh failed",
+            "nh home switch failed after 3s: Building the configuration",
+        ]
+    );
+}
+
+#[test]
+fn test_ntfy_request() {
+    assert_eq!(ntfy_url("my-builds"), "https://ntfy.sh/my-builds");
+    assert_eq!(
+        ntfy_url("https://ntfy.example.org/builds"),
+        "https://ntfy.example.org/builds"
+    );
+
+    let notification = Notification::finished("nh os boot", &Ok(()), Duration::from_secs(61));
+    assert_eq!(
+        ntfy_request("https://ntfy.sh/my-builds", &notification),
+        NtfyRequest {
+            url: String::from("https://ntfy.sh/my-builds"),
+            headers: vec![
+                ("Title", String::from("nh succeeded")),
+                ("Tags", String::from("white_check_mark")),
+                ("Priority", String::from("default")),
+            ],
+            body: String::from("nh os boot finished after 1m 1s"),
+        }
+    );
+
+    let failure = Notification {
+        title: String::from("nh failed"),
+        body: String::from("nh os boot failed after 1s: oops"),
+        success: false,
+    };
+    let request = ntfy_request("https://ntfy.sh/my-builds", &failure);
+    assert!(request.headers.contains(&("Tags", String::from("x"))));
+    assert!(request
+        .headers
+        .contains(&("Priority", String::from("high"))));
+    assert_eq!(request.body, "nh os boot failed after 1s: oops");
+}
@@ -0,0 +1,51 @@
+use color_eyre::Result;
+
+use crate::commands::{Command, CommandBuilder};
+use crate::interface::{NHRunnable, NixArgs};
+use crate::{elevate, util};
+
+impl NHRunnable for NixArgs {
+    fn run(&self) -> Result<()> {
+        self.command()?.exec()
+    }
+}
+
+impl NixArgs {
+    /// `nix` followed by the arguments exactly as they were given
+    fn command(&self) -> Result<Command> {
+        let mut cmd = if self.elevate {
+            elevate::command()
+        } else {
+            CommandBuilder::default()
+        };
+
+        Ok(cmd
+            .args([util::nix_bin()])
+            .args(&self.args)
+            .dry(self.dry)
+            .message("Running nix")
+            .build()?)
+    }
+}
+
+#[test]
+fn test_passthrough_args() {
+    use crate::interface::{NHCommand, NHParser};
+    use clap::Parser;
+
+    let command = |args: &[&str]| {
+        let parsed = NHParser::parse_from(["nh", "nix"].iter().chain(args));
+        let NHCommand::Nix(nix_args) = parsed.command else {
+            panic!("Expected nh nix");
+        };
+        nix_args.command().unwrap()
+    };
+
+    let cmd = command(&["-n", "--", "store", "gc", "--max", "1G", "-v"]);
+    assert_eq!(cmd.to_args(), ["nix", "store", "gc", "--max", "1G", "-v"]);
+    // Nothing runs in dry mode, even if nix isn't installed
+    cmd.exec().unwrap();
+
+    let cmd = command(&["--elevate", "--", "--version"]);
+    assert_eq!(cmd.to_args(), ["sudo", "nix", "--version"]);
+}
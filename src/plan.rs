@@ -0,0 +1,152 @@
+//! What a build would do, from the output of `nix build --dry-run`
+
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static SECTION_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?:these|this)(?: \d+)? (?:derivations?|paths?) will be (built|fetched)(?: \((.*)\))?:$",
+    )
+    .unwrap()
+});
+
+/// Derivations to build locally and paths to fetch from substituters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildPlan {
+    pub build: Vec<PathBuf>,
+    pub fetch: Vec<PathBuf>,
+    /// Sizes of the fetched paths as nix formats them, like `45.12 MiB download, 200.00 MiB unpacked`
+    pub fetch_size: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Section {
+    Build,
+    Fetch,
+}
+
+impl BuildPlan {
+    /// Collects the "will be built" and "will be fetched" lists, skipping any other line
+    pub fn parse(output: &str) -> Self {
+        let mut plan = Self::default();
+        let mut section = None;
+
+        for line in output.lines() {
+            if let Some(caps) = SECTION_REGEX.captures(line.trim()) {
+                section = match &caps[1] {
+                    "built" => Some(Section::Build),
+                    _ => {
+                        plan.fetch_size = caps.get(2).map(|size| size.as_str().to_string());
+                        Some(Section::Fetch)
+                    }
+                };
+                continue;
+            }
+
+            let path = line.trim();
+            match section {
+                Some(Section::Build) if line.starts_with(' ') && path.starts_with('/') => {
+                    plan.build.push(PathBuf::from(path))
+                }
+                Some(Section::Fetch) if line.starts_with(' ') && path.starts_with('/') => {
+                    plan.fetch.push(PathBuf::from(path))
+                }
+                _ => section = None,
+            }
+        }
+
+        plan
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.build.is_empty() && self.fetch.is_empty()
+    }
+
+    /// One line with the counts, like `3 derivations to build, 12 paths to fetch`
+    pub fn summary(&self) -> String {
+        if self.is_empty() {
+            return String::from("Nothing to build or fetch");
+        }
+
+        let plural = |n: usize, what: &str| format!("{n} {what}{}", if n == 1 { "" } else { "s" });
+        let mut summary = format!(
+            "{} to build, {} to fetch",
+            plural(self.build.len(), "derivation"),
+            plural(self.fetch.len(), "path")
+        );
+        if let Some(size) = &self.fetch_size {
+            summary.push_str(&format!(" ({size})"));
+        }
+        summary
+    }
+
+    /// The summary followed by both lists
+    pub fn details(&self) -> String {
+        let mut details = self.summary();
+        for (title, paths) in [("To build:", &self.build), ("To fetch:", &self.fetch)] {
+            if paths.is_empty() {
+                continue;
+            }
+            details.push('\n');
+            details.push_str(title);
+            for path in paths {
+                details.push_str(&format!("\n  {}", path.display()));
+            }
+        }
+        details
+    }
+}
+
+#[test]
+fn test_parse_plan() {
+    let output = "\
+warning: Git tree '/etc/nixos' is dirty
+these 2 derivations will be built:
+  /nix/store/aaa-etc.drv
+  /nix/store/bbb-nixos-system-host.drv
+these 3 paths will be fetched (45.12 MiB download, 200.00 MiB unpacked):
+  /nix/store/ccc-linux-6.6
+  /nix/store/ddd-firefox-120.0
+  /nix/store/eee-hello-2.12
+";
+    let plan = BuildPlan::parse(output);
+    assert_eq!(
+        plan,
+        BuildPlan {
+            build: vec![
+                PathBuf::from("/nix/store/aaa-etc.drv"),
+                PathBuf::from("/nix/store/bbb-nixos-system-host.drv"),
+            ],
+            fetch: vec![
+                PathBuf::from("/nix/store/ccc-linux-6.6"),
+                PathBuf::from("/nix/store/ddd-firefox-120.0"),
+                PathBuf::from("/nix/store/eee-hello-2.12"),
+            ],
+            fetch_size: Some(String::from("45.12 MiB download, 200.00 MiB unpacked")),
+        }
+    );
+    assert_eq!(
+        plan.summary(),
+        "2 derivations to build, 3 paths to fetch (45.12 MiB download, 200.00 MiB unpacked)"
+    );
+
+    // Singular headers, and older versions of nix without the counts or sizes
+    let plan = BuildPlan::parse(
+        "this derivation will be built:\n  /nix/store/aaa-foo.drv\nthese paths will be fetched:\n  /nix/store/bbb-bar\n",
+    );
+    assert_eq!(plan.build, [PathBuf::from("/nix/store/aaa-foo.drv")]);
+    assert_eq!(plan.fetch, [PathBuf::from("/nix/store/bbb-bar")]);
+    assert_eq!(plan.fetch_size, None);
+    assert_eq!(
+        plan.details(),
+        "1 derivation to build, 1 path to fetch\nTo build:\n  /nix/store/aaa-foo.drv\nTo fetch:\n  /nix/store/bbb-bar"
+    );
+
+    // Everything is already in the store
+    let plan = BuildPlan::parse("");
+    assert!(plan.is_empty());
+    assert_eq!(plan.summary(), "Nothing to build or fetch");
+    assert_eq!(plan.details(), "Nothing to build or fetch");
+}
@@ -0,0 +1,111 @@
+//! Tagging of every line of output, to tell apart commands running side by side
+
+use std::io::{self, Read, Write};
+
+use once_cell::sync::OnceCell;
+
+static OUTPUT_PREFIX: OnceCell<String> = OnceCell::new();
+
+/// Prefixes the output of every command with `[tag]`, from `--output-prefix`
+pub fn set_output_prefix(tag: Option<String>) {
+    if let Some(tag) = tag {
+        let _ = OUTPUT_PREFIX.set(format!("[{tag}] "));
+    }
+}
+
+/// Prefix set with [`set_output_prefix`], if any
+pub fn output_prefix() -> Option<String> {
+    OUTPUT_PREFIX.get().cloned()
+}
+
+/// Writer putting `prefix` in front of every line, holding back partial lines until their end
+/// is written
+#[derive(Debug)]
+pub struct PrefixWriter<W: Write> {
+    inner: W,
+    prefix: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> PrefixWriter<W> {
+    pub fn new(inner: W, prefix: &str) -> Self {
+        Self {
+            inner,
+            prefix: prefix.as_bytes().to_vec(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Writes out a partial last line, terminating it, and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.pending.push(b'\n');
+            self.write_lines()?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_lines(&mut self) -> io::Result<()> {
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            // A single write per line, so that lines of concurrent writers don't interleave
+            let mut tagged = Vec::with_capacity(self.prefix.len() + line.len());
+            tagged.extend_from_slice(&self.prefix);
+            tagged.extend_from_slice(&line);
+            self.inner.write_all(&tagged)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for PrefixWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.write_lines()?;
+        Ok(buf.len())
+    }
+
+    /// Flushes the complete lines only, as a partial line can't be prefixed twice
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies everything from `reader` to `writer`, prefixing every line
+pub fn copy_prefixed<R: Read, W: Write>(mut reader: R, writer: W, prefix: &str) -> io::Result<()> {
+    let mut writer = PrefixWriter::new(writer, prefix);
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()?;
+    Ok(())
+}
+
+#[test]
+fn test_prefix_writer() {
+    let mut writer = PrefixWriter::new(Vec::new(), "[hostA] ");
+
+    writer.write_all(b"building fo").unwrap();
+    // Nothing is written until the line is complete
+    writer.flush().unwrap();
+    assert!(writer.inner.is_empty());
+
+    writer.write_all(b"o\nbuilding bar\ncopying").unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&writer.inner),
+        "[hostA] building foo\n[hostA] building bar\n"
+    );
+
+    writer.write_all(b"\n\ndone").unwrap();
+    let output = writer.finish().unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "[hostA] building foo\n[hostA] building bar\n[hostA] copying\n[hostA] \n[hostA] done\n"
+    );
+
+    let mut output = Vec::new();
+    copy_prefixed(&b"one\ntwo"[..], &mut output, "[hostB] ").unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "[hostB] one\n[hostB] two\n"
+    );
+}
@@ -0,0 +1,211 @@
+//! Progress of a build as newline-delimited JSON, for editors and other tools to display
+//!
+//! Every line of `--progress-json` is an object with the same keys, like
+//! `{"phase":"build","current":3,"total":10,"message":"building '/nix/store/...'","timestamp":"..."}`.
+//! Counts are `null` until nix reports them, and the message is `null` for plain count updates.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::internal_json::{self, Event};
+
+/// Activity types of nix, from `ActivityType` in its logging header
+const ACTIVITY_COPY_PATH: u64 = 100;
+const ACTIVITY_COPY_PATHS: u64 = 103;
+const ACTIVITY_BUILDS: u64 = 104;
+const ACTIVITY_BUILD: u64 = 105;
+const ACTIVITY_SUBSTITUTE: u64 = 108;
+const ACTIVITY_FETCH_TREE: u64 = 112;
+
+/// Result type carrying the done and expected counts of an activity
+const RESULT_PROGRESS: u64 = 105;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Derivations being built
+    Build,
+    /// Paths and inputs being downloaded
+    Fetch,
+    /// Errors, warnings and other messages of nix
+    Log,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    pub phase: Phase,
+    pub current: Option<u64>,
+    pub total: Option<u64>,
+    pub message: Option<String>,
+    /// RFC 3339 in UTC, like `2024-05-01T12:00:00.123Z`
+    pub timestamp: String,
+}
+
+impl Progress {
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).expect("Progress always serializes")
+    }
+}
+
+/// Turns the internal-json stream of nix into [`Progress`] updates
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    /// Phase of the activities that were started, by activity id
+    activities: HashMap<u64, Phase>,
+    /// Latest done and expected counts of each phase
+    counts: HashMap<Phase, (u64, u64)>,
+}
+
+impl ProgressTracker {
+    /// Consumes a line of the output of nix, returning the update to report, if any
+    pub fn observe(&mut self, line: &str, at: SystemTime) -> Option<Progress> {
+        let Some(event) = internal_json::parse_line(line) else {
+            // Output paths and blank lines aren't progress, anything else is a message
+            let text = internal_json::strip_ansi(line.trim());
+            if text.is_empty() || text.starts_with("/nix/store/") {
+                return None;
+            }
+            return Some(self.update(Phase::Log, Some(text), at));
+        };
+
+        match event {
+            Event::Msg { level, msg } if level <= 1 => {
+                let msg = internal_json::strip_ansi(&msg);
+                Some(self.update(Phase::Log, Some(msg), at))
+            }
+            Event::Start {
+                id,
+                activity_type,
+                text,
+                ..
+            } => {
+                let phase = match activity_type {
+                    ACTIVITY_BUILDS | ACTIVITY_BUILD => Phase::Build,
+                    ACTIVITY_COPY_PATHS | ACTIVITY_COPY_PATH | ACTIVITY_SUBSTITUTE
+                    | ACTIVITY_FETCH_TREE => Phase::Fetch,
+                    _ => return None,
+                };
+                self.activities.insert(id, phase);
+
+                // The aggregate activities have no text, they only carry counts
+                (!text.is_empty()).then(|| self.update(phase, Some(text), at))
+            }
+            Event::Result {
+                id,
+                result_type: RESULT_PROGRESS,
+                fields,
+            } => {
+                let phase = *self.activities.get(&id)?;
+                let count = |i: usize| fields.get(i).and_then(serde_json::Value::as_u64);
+                let (done, expected) = (count(0)?, count(1)?);
+                // Individual downloads report bytes, only the aggregates count paths
+                if expected == 0 || self.counts.get(&phase) == Some(&(done, expected)) {
+                    return None;
+                }
+                self.counts.insert(phase, (done, expected));
+                Some(self.update(phase, None, at))
+            }
+            Event::Stop { id } => {
+                self.activities.remove(&id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn update(&self, phase: Phase, message: Option<String>, at: SystemTime) -> Progress {
+        let counts = self.counts.get(&phase);
+        Progress {
+            phase,
+            current: counts.map(|(done, _)| *done),
+            total: counts.map(|(_, expected)| *expected),
+            message,
+            timestamp: humantime::format_rfc3339_millis(at).to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_progress_json() {
+    use std::time::Duration;
+
+    let transcript = [
+        r#"warning: Git tree '/etc/nixos' is dirty"#,
+        r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","fields":[]}"#,
+        r#"@nix {"action":"start","id":2,"level":0,"type":103,"text":"","fields":[]}"#,
+        r#"@nix {"action":"result","id":1,"type":105,"fields":[0,2,0,0]}"#,
+        r#"@nix {"action":"start","id":3,"level":4,"type":108,"text":"copying '/nix/store/ccc-baz' from 'https://cache.nixos.org'","fields":["/nix/store/ccc-baz","https://cache.nixos.org"]}"#,
+        r#"@nix {"action":"result","id":2,"type":105,"fields":[1,1,0,0]}"#,
+        r#"@nix {"action":"stop","id":3}"#,
+        r#"@nix {"action":"start","id":4,"level":3,"type":105,"text":"building '/nix/store/aaa-foo.drv'","fields":["/nix/store/aaa-foo.drv","",1,1]}"#,
+        r#"@nix {"action":"result","id":4,"type":101,"fields":["compiling foo.c"]}"#,
+        r#"@nix {"action":"stop","id":4}"#,
+        r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,0,0]}"#,
+        r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,0,0]}"#,
+        r#"@nix {"action":"msg","level":0,"msg":"error: builder for '/nix/store/bbb-bar.drv' failed"}"#,
+        r#"@nix {"action":"msg","level":3,"msg":"evaluating file '/etc/nixos/flake.nix'"}"#,
+        "/nix/store/ddd-result",
+    ];
+
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut tracker = ProgressTracker::default();
+    let lines: Vec<String> = transcript
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| tracker.observe(line, start + Duration::from_millis(i as u64)))
+        .map(|progress| progress.to_json_line())
+        .collect();
+
+    // Every line is a self-contained object with the same keys
+    let objects: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|line| {
+            assert!(!line.contains('\n'));
+            serde_json::from_str(line).unwrap()
+        })
+        .collect();
+    for object in &objects {
+        let keys: Vec<&str> = object
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        // serde_json sorts the keys it parses
+        assert_eq!(keys, ["current", "message", "phase", "timestamp", "total"]);
+    }
+
+    let summary: Vec<_> = objects
+        .iter()
+        .map(|o| {
+            (
+                o["phase"].as_str().unwrap(),
+                o["current"].as_u64(),
+                o["total"].as_u64(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("log", None, None),
+            ("build", Some(0), Some(2)),
+            ("fetch", None, None),
+            ("fetch", Some(1), Some(1)),
+            ("build", Some(0), Some(2)),
+            ("build", Some(1), Some(2)),
+            ("log", None, None),
+        ]
+    );
+
+    assert_eq!(
+        objects[0]["message"],
+        "warning: Git tree '/etc/nixos' is dirty"
+    );
+    assert_eq!(objects[1]["message"], serde_json::Value::Null);
+    assert_eq!(objects[4]["message"], "building '/nix/store/aaa-foo.drv'");
+    assert_eq!(objects[0]["timestamp"], "2023-11-14T22:13:20.000Z");
+    assert_eq!(objects[6]["timestamp"], "2023-11-14T22:13:20.012Z");
+}
@@ -45,7 +45,7 @@ impl NHRunnable for SearchArgs {
         trace!("args: {self:?}");
 
         let nixpkgs_path = std::thread::spawn(|| {
-            std::process::Command::new("nix")
+            std::process::Command::new(util::nix_bin())
                 .stderr(Stdio::inherit())
                 .args(["eval", "nixpkgs#path"])
                 .output()
@@ -113,7 +113,10 @@ impl NHRunnable for SearchArgs {
         };
         debug!(?channel);
 
-        println!("Querying search.nixos.org, with channel {}...", channel);
+        let quiet = crate::logging::is_quiet();
+        if !quiet {
+            println!("Querying search.nixos.org, with channel {}...", channel);
+        }
         let then = Instant::now();
 
         let client = reqwest::blocking::Client::new();
@@ -140,9 +143,11 @@ impl NHRunnable for SearchArgs {
         let elapsed = then.elapsed();
         debug!(?elapsed);
         trace!(?response);
-        println!("Took {}ms", elapsed.as_millis());
-        println!("Most relevant results at the end");
-        println!();
+        if !quiet {
+            println!("Took {}ms", elapsed.as_millis());
+            println!("Most relevant results at the end");
+            println!();
+        }
 
         let parsed_response: SearchResponse = response
             .json()
@@ -214,7 +219,7 @@ impl NHRunnable for SearchArgs {
 }
 
 fn my_nix_branch(flake: &FlakeRef) -> Result<String> {
-    let output = std::process::Command::new("nix")
+    let output = std::process::Command::new(util::nix_bin())
         .args(["flake", "metadata", "--json"])
         .arg(flake.as_str())
         .output()?;
@@ -247,17 +252,17 @@ fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
     }
 
     let re = Regex::new(r"nixos-[0-9]+\.[0-9]+").unwrap();
-    return re.is_match(branch);
+    re.is_match(branch)
 }
 
 #[test]
 fn test_supported_branch() {
-    assert_eq!(supported_branch("nixos-unstable"), true);
-    assert_eq!(supported_branch("nixos-unstable-small"), false);
-    assert_eq!(supported_branch("nixos-24.05"), true);
-    assert_eq!(supported_branch("24.05"), false);
-    assert_eq!(supported_branch("nixpkgs-darwin"), false);
-    assert_eq!(supported_branch("nixpks-21.11-darwin"), false);
+    assert!(supported_branch("nixos-unstable"));
+    assert!(!supported_branch("nixos-unstable-small"));
+    assert!(supported_branch("nixos-24.05"));
+    assert!(!supported_branch("24.05"));
+    assert!(!supported_branch("nixpkgs-darwin"));
+    assert!(!supported_branch("nixpks-21.11-darwin"));
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -0,0 +1,110 @@
+//! Forwarding of SIGTERM to the commands nh is running
+//!
+//! A terminal delivers SIGINT to the whole foreground process group, but a service manager
+//! stopping nh only signals nh itself. The handler passes the signal on to every child that is
+//! being waited on, so that the build fails and nh exits through its usual error path.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use nix::libc;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+
+/// Most children nh runs at once, like nix piped into nom
+const SLOTS: usize = 8;
+
+/// Pids of the running children, 0 for a free slot
+///
+/// A plain array of atomics, as the signal handler can't take locks or allocate.
+static CHILDREN: [AtomicI32; SLOTS] = [const { AtomicI32::new(0) }; SLOTS];
+
+/// Installs the handler forwarding SIGTERM
+pub fn forward_termination() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_termination),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only touches atomics and calls async-signal-safe functions
+    unsafe { signal::sigaction(Signal::SIGTERM, &action) }
+        .wrap_err("Installing the SIGTERM handler")?;
+    Ok(())
+}
+
+extern "C" fn handle_termination(signal: libc::c_int) {
+    if !forward_to(&CHILDREN, signal) {
+        // Nothing to wait for, so terminate like the default action would
+        // SAFETY: _exit is async-signal-safe
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Sends `signal` to every child in `children`, returning whether there was any
+fn forward_to(children: &[AtomicI32], signal: libc::c_int) -> bool {
+    let Ok(signal) = Signal::try_from(signal) else {
+        return false;
+    };
+
+    let mut forwarded = false;
+    for slot in children {
+        let pid = slot.load(Ordering::SeqCst);
+        if pid > 0 {
+            let _ = signal::kill(Pid::from_raw(pid), signal);
+            forwarded = true;
+        }
+    }
+    forwarded
+}
+
+/// Registration of a running child, undone when dropped
+#[derive(Debug)]
+pub struct Tracked<'a> {
+    slot: Option<&'a AtomicI32>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            slot.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Forwards SIGTERM to `pid` for as long as the returned guard lives
+pub fn track(pid: Option<u32>) -> Tracked<'static> {
+    track_in(&CHILDREN, pid)
+}
+
+fn track_in(children: &[AtomicI32], pid: Option<u32>) -> Tracked<'_> {
+    let slot = pid.and_then(|pid| i32::try_from(pid).ok()).and_then(|pid| {
+        children.iter().find(|slot| {
+            slot.compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })
+    });
+
+    Tracked { slot }
+}
+
+#[test]
+fn test_forward_termination() {
+    use subprocess::{Exec, ExitStatus};
+
+    let children: [AtomicI32; 2] = [const { AtomicI32::new(0) }; 2];
+    assert!(!forward_to(&children, libc::SIGTERM));
+
+    let mut child = Exec::cmd("sleep").arg("30").popen().unwrap();
+    let tracked = track_in(&children, child.pid());
+    assert!(forward_to(&children, libc::SIGTERM));
+    assert_eq!(
+        child.wait().unwrap(),
+        ExitStatus::Signaled(libc::SIGTERM as u8)
+    );
+
+    // The slot is free again once the child is no longer waited on
+    drop(tracked);
+    assert!(children.iter().all(|slot| slot.load(Ordering::SeqCst) == 0));
+    assert!(!forward_to(&children, libc::SIGTERM));
+}
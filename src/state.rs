@@ -0,0 +1,233 @@
+//! Persistent state of nh, like the labels and tags of generations
+//!
+//! Everything lives in a single `state.json`, keyed by profile since generation numbers are only
+//! unique within one.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Root-owned location for the state of the system profile, used if its directory exists
+const SYSTEM_STATE_DIR: &str = "/var/lib/nh";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    profiles: BTreeMap<PathBuf, ProfileState>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileState {
+    #[serde(default)]
+    labels: BTreeMap<u32, Label>,
+    #[serde(default)]
+    tags: BTreeMap<String, Tag>,
+}
+
+/// Human description of a generation, which nix profiles can't hold themselves
+///
+/// Generation numbers are reused after old generations are deleted, so a label is only valid for
+/// the store path it was recorded with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Label {
+    store_path: PathBuf,
+    description: String,
+}
+
+/// Name given to a generation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tag {
+    pub generation: u32,
+    pub store_path: PathBuf,
+}
+
+impl State {
+    /// `$XDG_STATE_HOME/nh/state.json`, or `/var/lib/nh/state.json` for the system profile if
+    /// that directory was created and can be written
+    pub fn path_for(profile: &Path) -> Result<PathBuf> {
+        let system_dir = Path::new(SYSTEM_STATE_DIR);
+        if profile == Path::new(SYSTEM_PROFILE)
+            && system_dir.is_dir()
+            && crate::flake::is_writable(system_dir)
+        {
+            return Ok(system_dir.join("state.json"));
+        }
+
+        Ok(
+            state_dir(std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME"))?
+                .join("state.json"),
+        )
+    }
+
+    /// Reads the state from `path`, which may not exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .wrap_err_with(|| format!("Parsing the state from {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).wrap_err_with(|| format!("Reading {path:?}")),
+        }
+    }
+
+    /// Writes the state to a temporary file next to `path`, then moves it into place
+    ///
+    /// A crash can't leave a truncated file behind, as the rename replaces the old state at once.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir).wrap_err_with(|| format!("Creating {dir:?}"))?;
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .wrap_err_with(|| format!("Creating a temporary file in {dir:?}"))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.as_file().sync_all()?;
+        file.persist(path)
+            .wrap_err_with(|| format!("Writing {path:?}"))?;
+
+        Ok(())
+    }
+
+    pub fn profile(&self, profile: &Path) -> Option<&ProfileState> {
+        self.profiles.get(profile)
+    }
+
+    pub fn profile_mut(&mut self, profile: &Path) -> &mut ProfileState {
+        self.profiles.entry(profile.to_owned()).or_default()
+    }
+}
+
+impl ProfileState {
+    pub fn label(&self, number: u32, store_path: &Path) -> Option<&str> {
+        self.labels
+            .get(&number)
+            .filter(|label| label.store_path == store_path)
+            .map(|label| label.description.as_str())
+    }
+
+    pub fn set_label(&mut self, number: u32, store_path: &Path, description: &str) {
+        self.labels.insert(
+            number,
+            Label {
+                store_path: store_path.to_owned(),
+                description: description.to_string(),
+            },
+        );
+    }
+
+    #[cfg(test)]
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        self.tags.get(name)
+    }
+
+    #[cfg(test)]
+    pub fn set_tag(&mut self, name: &str, generation: u32, store_path: &Path) {
+        self.tags.insert(
+            name.to_string(),
+            Tag {
+                generation,
+                store_path: store_path.to_owned(),
+            },
+        );
+    }
+}
+
+/// `$XDG_STATE_HOME/nh`, falling back to `~/.local/state/nh`
+fn state_dir(xdg_state_home: Option<OsString>, home: Option<OsString>) -> Result<PathBuf> {
+    let state_home = match (xdg_state_home, home) {
+        // Relative paths are invalid according to the spec
+        (Some(dir), _) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        (_, Some(home)) => PathBuf::from(home).join(".local/state"),
+        (_, None) => bail!("Neither XDG_STATE_HOME nor HOME are set"),
+    };
+    Ok(state_home.join("nh"))
+}
+
+#[test]
+fn test_state_dir() {
+    assert_eq!(
+        state_dir(Some("/state".into()), Some("/home/user".into())).unwrap(),
+        PathBuf::from("/state/nh")
+    );
+    assert_eq!(
+        state_dir(None, Some("/home/user".into())).unwrap(),
+        PathBuf::from("/home/user/.local/state/nh")
+    );
+    assert_eq!(
+        state_dir(Some("relative".into()), Some("/home/user".into())).unwrap(),
+        PathBuf::from("/home/user/.local/state/nh")
+    );
+    assert!(state_dir(None, None).is_err());
+}
+
+#[test]
+fn test_state_atomic_save() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nh/state.json");
+    let system = Path::new(SYSTEM_PROFILE);
+    let store_path = Path::new("/nix/store/abc-nixos-system");
+
+    let mut state = State::load(&path).unwrap();
+    state
+        .profile_mut(system)
+        .set_label(42, store_path, "upgrade firefox");
+    state
+        .profile_mut(system)
+        .set_tag("known-good", 42, store_path);
+    state.save(&path).unwrap();
+
+    // A crash while writing leaves only a stray temporary file, never a partial state.json
+    let stray = tempfile::NamedTempFile::new_in(path.parent().unwrap()).unwrap();
+    std::fs::write(stray.path(), "{ truncated").unwrap();
+    std::mem::forget(stray);
+
+    let mut state = State::load(&path).unwrap();
+    let profile = state.profile(system).unwrap();
+    assert_eq!(profile.label(42, store_path), Some("upgrade firefox"));
+    assert_eq!(
+        profile.tag("known-good"),
+        Some(&Tag {
+            generation: 42,
+            store_path: store_path.to_owned()
+        })
+    );
+
+    // Saving again replaces the file as a whole
+    state.profile_mut(system).set_label(43, store_path, "other");
+    state.save(&path).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let reloaded: State = serde_json::from_str(&contents).unwrap();
+    assert_eq!(
+        reloaded.profile(system).unwrap().label(43, store_path),
+        Some("other")
+    );
+}
+
+#[test]
+fn test_generation_labels() {
+    let mut profile = ProfileState::default();
+    profile.set_label(
+        42,
+        Path::new("/nix/store/abc-nixos-system"),
+        "upgrade firefox",
+    );
+
+    assert_eq!(
+        profile.label(42, Path::new("/nix/store/abc-nixos-system")),
+        Some("upgrade firefox")
+    );
+    // Same number, but the generation was deleted and replaced
+    assert_eq!(
+        profile.label(42, Path::new("/nix/store/def-nixos-system")),
+        None
+    );
+    assert_eq!(
+        profile.label(43, Path::new("/nix/store/abc-nixos-system")),
+        None
+    );
+}
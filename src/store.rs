@@ -0,0 +1,108 @@
+//! Maintenance of the nix store, besides garbage collection
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::commands::{self, Command};
+use crate::interface::{NHRunnable, StoreArgs, StoreOptimiseArgs, StoreSubcommand};
+use crate::{elevate, util};
+
+static FREED_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"([\d.]+ \w+) freed by hard-linking (\d+) files").unwrap());
+
+impl NHRunnable for StoreArgs {
+    fn run(&self) -> Result<()> {
+        match &self.subcommand {
+            StoreSubcommand::Optimise(args) => args.optimise(),
+        }
+    }
+}
+
+/// Space saved by `nix store optimise`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Optimised {
+    /// Freed space as nix formats it, like `1.20 MiB`
+    freed: String,
+    files: u64,
+}
+
+/// Finds the summary line in the output of `nix store optimise`
+fn parse_optimised(output: &str) -> Option<Optimised> {
+    output.lines().find_map(|line| {
+        let caps = FREED_REGEX.captures(line)?;
+        Some(Optimised {
+            freed: caps[1].to_string(),
+            files: caps[2].parse().ok()?,
+        })
+    })
+}
+
+/// `nix store optimise`, through sudo unless nh already runs as root
+fn optimise_command(dry: bool, elevated: bool) -> Result<Command> {
+    let mut cmd = if elevated {
+        elevate::command()
+    } else {
+        commands::CommandBuilder::default()
+    };
+
+    Ok(cmd
+        .args([util::nix_bin(), "store", "optimise"])
+        .dry(dry)
+        .message("Optimising the nix store by hard-linking identical files, this can take a while")
+        .merge_stderr(true)
+        .build()?)
+}
+
+impl StoreOptimiseArgs {
+    fn optimise(&self) -> Result<()> {
+        if self.dry {
+            warn!("nix can't estimate the savings of optimising, only printing the command");
+        }
+
+        let elevated = !nix::unistd::Uid::effective().is_root();
+        let Some(output) = optimise_command(self.dry, elevated)?
+            .exec_capture()
+            .wrap_err("Optimising the nix store")?
+        else {
+            return Ok(());
+        };
+
+        match parse_optimised(&output) {
+            Some(Optimised { freed, files }) => {
+                info!("Freed {freed} by hard-linking {files} files")
+            }
+            None => info!("Done, nix didn't report the space it freed"),
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_optimise() {
+    let cmd = optimise_command(false, true).unwrap();
+    assert_eq!(cmd.to_args(), ["sudo", "nix", "store", "optimise"]);
+    let cmd = optimise_command(true, false).unwrap();
+    assert_eq!(cmd.to_args(), ["nix", "store", "optimise"]);
+
+    assert_eq!(
+        parse_optimised(
+            "these paths will be optimised\n1306.21 MiB freed by hard-linking 37617 files\n"
+        ),
+        Some(Optimised {
+            freed: String::from("1306.21 MiB"),
+            files: 37617,
+        })
+    );
+    assert_eq!(
+        parse_optimised("0.00 KiB freed by hard-linking 0 files"),
+        Some(Optimised {
+            freed: String::from("0.00 KiB"),
+            files: 0,
+        })
+    );
+    assert_eq!(parse_optimised(""), None);
+}
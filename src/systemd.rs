@@ -0,0 +1,190 @@
+//! Queries to systemd about the state of the units after an activation
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use color_eyre::eyre::ContextCompat;
+use color_eyre::Result;
+
+use crate::{commands, elevate};
+
+/// Names of the units currently in the failed state
+pub fn failed_units() -> Result<BTreeSet<String>> {
+    let mut units = BTreeSet::new();
+    commands::CommandBuilder::default()
+        .args([
+            "systemctl",
+            "list-units",
+            "--failed",
+            "--plain",
+            "--no-legend",
+        ])
+        .build()?
+        .exec_stream(|line| {
+            units.extend(parse_failed_unit(line));
+            Ok(())
+        })?
+        .wrap_err("Capturing failed units")?;
+
+    Ok(units)
+}
+
+/// Parses a line of `systemctl list-units --failed --plain --no-legend`
+fn parse_failed_unit(line: &str) -> Option<String> {
+    line.split_whitespace()
+        .find(|field| *field != "●")
+        .map(String::from)
+}
+
+#[cfg(test)]
+fn parse_failed_units(output: &str) -> BTreeSet<String> {
+    output.lines().filter_map(parse_failed_unit).collect()
+}
+
+/// Units that are failed after the activation, but weren't before
+pub fn new_failures<'a>(before: &'a BTreeSet<String>, after: &'a BTreeSet<String>) -> Vec<&'a str> {
+    after.difference(before).map(String::as_str).collect()
+}
+
+/// Suffixes of the unit types systemd knows about
+const UNIT_TYPES: &[&str] = &[
+    "service",
+    "socket",
+    "device",
+    "mount",
+    "automount",
+    "swap",
+    "target",
+    "path",
+    "timer",
+    "slice",
+    "scope",
+];
+
+/// Parses a unit name of the command line, where a missing type means a service
+pub fn parse_unit(unit: &str) -> Result<String, String> {
+    if unit.is_empty() || unit.contains(char::is_whitespace) {
+        return Err(format!("{unit:?} isn't a valid unit name"));
+    }
+
+    match unit.rsplit_once('.') {
+        Some((_, suffix)) if UNIT_TYPES.contains(&suffix) => Ok(unit.to_string()),
+        _ => Ok(format!("{unit}.service")),
+    }
+}
+
+/// Units that switch-to-configuration would restart, according to its dry-activate output
+pub fn parse_would_restart(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("would restart the following units: ")
+        })
+        .flat_map(|units| units.split(", "))
+        .map(|unit| unit.trim().to_string())
+        .filter(|unit| !unit.is_empty())
+        .collect()
+}
+
+/// Asks switch-to-configuration of `profile` which units it would restart
+pub fn would_restart(profile: &Path) -> Result<BTreeSet<String>> {
+    let switch_to_configuration = profile.join("bin").join("switch-to-configuration");
+    let output = elevate::command()
+        .args([switch_to_configuration.as_os_str(), "dry-activate".as_ref()])
+        .message("Checking which units the activation restarts")
+        .merge_stderr(true)
+        .build()?
+        .exec_capture()?
+        .wrap_err("Capturing the dry activation")?;
+
+    Ok(parse_would_restart(&output))
+}
+
+/// Masks the units until the next reboot, or unmasks them again
+pub fn mask_command<'a, I>(units: I, mask: bool) -> Result<commands::Command>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let action = if mask { "mask" } else { "unmask" };
+    Ok(elevate::command()
+        .args(["systemctl", action, "--runtime", "--"])
+        .args(units)
+        .build()?)
+}
+
+/// Lists the units the activation didn't restart, for the user to restart them later
+pub fn restart_report(skipped: &BTreeSet<String>) -> String {
+    let units: Vec<&str> = skipped.iter().map(String::as_str).collect();
+    format!(
+        "Not restarted: {}\nRestart them with: sudo systemctl restart {}",
+        units.join(", "),
+        units.join(" ")
+    )
+}
+
+/// Prints the last journal entries of a unit
+pub fn journal_command(unit: &str) -> Result<commands::Command> {
+    Ok(commands::CommandBuilder::default()
+        .args(["journalctl", "--unit", unit, "--lines", "20", "--no-pager"])
+        .message(format!("Recent logs of {unit}"))
+        .build()?)
+}
+
+#[test]
+fn test_new_failures() {
+    let before = parse_failed_units(
+        "foo.service loaded failed failed Foo daemon\n\
+         bar.mount   loaded failed failed /bar\n",
+    );
+    let after = parse_failed_units(
+        "● bar.mount loaded failed failed /bar\n\
+         ● baz.service loaded failed failed Baz\n\
+         qux.timer loaded failed failed Qux\n",
+    );
+
+    assert_eq!(
+        before,
+        BTreeSet::from(["foo.service".to_string(), "bar.mount".to_string()])
+    );
+    assert_eq!(new_failures(&before, &after), ["baz.service", "qux.timer"]);
+    assert!(new_failures(&after, &after).is_empty());
+    assert!(parse_failed_units("").is_empty());
+}
+
+#[test]
+fn test_parse_unit() {
+    assert_eq!(parse_unit("nginx").unwrap(), "nginx.service");
+    assert_eq!(parse_unit("nginx.service").unwrap(), "nginx.service");
+    assert_eq!(parse_unit("backup.timer").unwrap(), "backup.timer");
+    assert_eq!(parse_unit("getty@tty1").unwrap(), "getty@tty1.service");
+    // Dots are allowed in the name itself
+    assert_eq!(parse_unit("app.v2").unwrap(), "app.v2.service");
+    assert!(parse_unit("").is_err());
+    assert!(parse_unit("two units").is_err());
+}
+
+#[test]
+fn test_restart_report() {
+    let would_restart = parse_would_restart(
+        "would stop the following units: old.service\n\
+         would restart systemd\n\
+         would restart the following units: nginx.service, postgresql.service\n\
+         would reload the following units: dbus.service\n\
+         would start the following units: new.service\n",
+    );
+    assert_eq!(
+        would_restart,
+        BTreeSet::from([
+            "nginx.service".to_string(),
+            "postgresql.service".to_string()
+        ])
+    );
+    assert!(parse_would_restart("would start the following units: a.service\n").is_empty());
+
+    assert_eq!(
+        restart_report(&would_restart),
+        "Not restarted: nginx.service, postgresql.service\n\
+         Restart them with: sudo systemctl restart nginx.service postgresql.service"
+    );
+}
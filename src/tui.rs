@@ -0,0 +1,244 @@
+//! Interactive browser for the generations of a profile
+//!
+//! The selection logic is always compiled, the terminal frontend needs the `tui` feature.
+#![cfg_attr(not(feature = "tui"), allow(dead_code))]
+
+use crate::generations::GenerationInfo;
+
+/// Input of the browser, independent of the terminal backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    First,
+    Last,
+    Diff,
+    Enter,
+    Yes,
+    No,
+    Quit,
+}
+
+/// What the user chose to do with a generation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Diff(GenerationInfo),
+    Rollback(GenerationInfo),
+    Quit,
+}
+
+/// A generation along with its description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub generation: GenerationInfo,
+    pub label: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct GenerationBrowser {
+    entries: Vec<Entry>,
+    selected: usize,
+    /// Whether the rollback to the selected generation waits for a confirmation
+    confirming: bool,
+}
+
+impl GenerationBrowser {
+    /// Starts on the current generation, or the latest if none is current
+    pub fn new(entries: Vec<Entry>) -> Self {
+        let selected = entries
+            .iter()
+            .position(|entry| entry.generation.current)
+            .unwrap_or(entries.len().saturating_sub(1));
+
+        Self {
+            entries,
+            selected,
+            confirming: false,
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.entries.get(self.selected)
+    }
+
+    pub fn is_confirming(&self) -> bool {
+        self.confirming
+    }
+
+    /// Moves the selection or the confirmation forward, returning the action once one is chosen
+    pub fn handle(&mut self, key: Key) -> Option<Action> {
+        if self.confirming {
+            self.confirming = false;
+            return match key {
+                Key::Yes | Key::Enter => self
+                    .selected()
+                    .map(|e| Action::Rollback(e.generation.clone())),
+                Key::Quit => Some(Action::Quit),
+                _ => None,
+            };
+        }
+
+        let last = self.entries.len().saturating_sub(1);
+        match key {
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => self.selected = (self.selected + 1).min(last),
+            Key::First => self.selected = 0,
+            Key::Last => self.selected = last,
+            Key::Diff => return self.selected().map(|e| Action::Diff(e.generation.clone())),
+            Key::Enter => self.confirming = self.selected().is_some(),
+            Key::Quit => return Some(Action::Quit),
+            Key::Yes | Key::No => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(feature = "tui")]
+pub use frontend::run;
+
+#[cfg(feature = "tui")]
+mod frontend {
+    use color_eyre::Result;
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Style, Stylize};
+    use ratatui::widgets::{List, ListItem, ListState, Paragraph};
+    use ratatui::DefaultTerminal;
+
+    use super::{Action, GenerationBrowser, Key};
+
+    /// Shows the browser until an action is chosen, restoring the terminal afterwards
+    pub fn run(browser: GenerationBrowser) -> Result<Action> {
+        let mut terminal = ratatui::init();
+        let result = event_loop(&mut terminal, browser);
+        ratatui::restore();
+        result
+    }
+
+    fn event_loop(
+        terminal: &mut DefaultTerminal,
+        mut browser: GenerationBrowser,
+    ) -> Result<Action> {
+        loop {
+            terminal.draw(|frame| {
+                let [list_area, footer_area] =
+                    Layout::vertical([Constraint::Min(1), Constraint::Length(1)])
+                        .areas(frame.area());
+
+                let items: Vec<ListItem> = browser
+                    .entries()
+                    .iter()
+                    .map(|entry| {
+                        let generation = &entry.generation;
+                        let date = humantime::format_rfc3339_seconds(generation.last_modified);
+                        let mut line = format!("{}  {date}", generation.number);
+                        if generation.current {
+                            line.push_str(" (current)");
+                        }
+                        if let Some(label) = &entry.label {
+                            line.push_str(&format!("  {label}"));
+                        }
+                        ListItem::new(line)
+                    })
+                    .collect();
+
+                let list = List::new(items)
+                    .highlight_style(Style::new().reversed())
+                    .highlight_symbol("> ");
+                let mut state = ListState::default().with_selected(Some(browser.selected));
+                frame.render_stateful_widget(list, list_area, &mut state);
+
+                let footer = match (browser.is_confirming(), browser.selected()) {
+                    (true, Some(entry)) => {
+                        format!("Roll back to generation {}? [y/N]", entry.generation.number)
+                    }
+                    _ => String::from("↑/↓ select  d diff  enter roll back  q quit"),
+                };
+                frame.render_widget(Paragraph::new(footer).bold(), footer_area);
+            })?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            let key = match key.code {
+                KeyCode::Up | KeyCode::Char('k') => Key::Up,
+                KeyCode::Down | KeyCode::Char('j') => Key::Down,
+                KeyCode::Home | KeyCode::Char('g') => Key::First,
+                KeyCode::End | KeyCode::Char('G') => Key::Last,
+                KeyCode::Char('d') => Key::Diff,
+                KeyCode::Enter => Key::Enter,
+                KeyCode::Char('y') => Key::Yes,
+                KeyCode::Char('n') => Key::No,
+                KeyCode::Esc | KeyCode::Char('q') => Key::Quit,
+                _ => continue,
+            };
+
+            if let Some(action) = browser.handle(key) {
+                return Ok(action);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_browser_selection() {
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    let entry = |number, current| Entry {
+        generation: GenerationInfo {
+            number,
+            path: PathBuf::from(format!("/nix/store/{number}-nixos-system")),
+            last_modified: SystemTime::UNIX_EPOCH,
+            current,
+        },
+        label: None,
+    };
+    let selected = |browser: &GenerationBrowser| browser.selected().unwrap().generation.number;
+
+    let mut browser =
+        GenerationBrowser::new(vec![entry(1, false), entry(2, true), entry(3, false)]);
+    assert_eq!(selected(&browser), 2);
+
+    // The selection stays within the list
+    assert_eq!(browser.handle(Key::Down), None);
+    assert_eq!(browser.handle(Key::Down), None);
+    assert_eq!(selected(&browser), 3);
+    browser.handle(Key::First);
+    browser.handle(Key::Up);
+    assert_eq!(selected(&browser), 1);
+
+    assert_eq!(
+        browser.handle(Key::Diff),
+        Some(Action::Diff(entry(1, false).generation))
+    );
+
+    // Rolling back needs a confirmation, and anything else cancels it
+    assert_eq!(browser.handle(Key::Enter), None);
+    assert!(browser.is_confirming());
+    assert_eq!(browser.handle(Key::No), None);
+    assert!(!browser.is_confirming());
+    browser.handle(Key::Last);
+    browser.handle(Key::Enter);
+    assert_eq!(
+        browser.handle(Key::Yes),
+        Some(Action::Rollback(entry(3, false).generation))
+    );
+
+    assert_eq!(browser.handle(Key::Quit), Some(Action::Quit));
+
+    let mut empty = GenerationBrowser::new(Vec::new());
+    assert_eq!(empty.handle(Key::Down), None);
+    assert_eq!(empty.handle(Key::Enter), None);
+    assert!(!empty.is_confirming());
+    assert_eq!(empty.handle(Key::Diff), None);
+}
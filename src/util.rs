@@ -1,10 +1,14 @@
 extern crate semver;
 
-use color_eyre::{eyre, Result};
+use color_eyre::eyre::{self, bail, Context};
+use color_eyre::Result;
+use once_cell::sync::OnceCell;
 use semver::Version;
 
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::str::FromStr;
 
 /// Compares two semantic versions and returns their order.
 ///
@@ -27,32 +31,250 @@ pub fn compare_semver(current: &str, target: &str) -> Result<std::cmp::Ordering>
     Ok(current.cmp(&target))
 }
 
-/// Retrieves the installed Nix version as a string.
-///
-/// This function executes the `nix --version` command, parses the output to extract the version string,
-/// and returns it. If the version string cannot be found or parsed, it returns an error.
+/// Implementation of nix, which differ in some of their flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NixVariant {
+    /// The reference implementation
+    #[value(name = "cnix")]
+    CNix,
+    /// The Lix fork of nix 2.18
+    Lix,
+}
+
+static NIX_BIN: OnceCell<String> = OnceCell::new();
+static NIX_VARIANT: OnceCell<NixVariant> = OnceCell::new();
+
+/// Sets the nix binary and implementation to use instead of the detected ones
+pub fn configure_nix(bin: Option<String>, variant: Option<NixVariant>) {
+    if let Some(bin) = bin {
+        let _ = NIX_BIN.set(bin);
+    }
+    if let Some(variant) = variant {
+        let _ = NIX_VARIANT.set(variant);
+    }
+}
+
+/// The nix binary to run, `nix` unless overridden
+pub fn nix_bin() -> &'static str {
+    NIX_BIN.get().map_or("nix", String::as_str)
+}
+
+/// Version of the nix implementation in use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixVersion {
+    pub variant: NixVariant,
+    pub version: String,
+}
+
+impl NixVersion {
+    /// Parses the output of `nix --version`, like `nix (Lix, like Nix) 2.91.1`
+    pub fn parse(output: &str) -> Result<Self> {
+        let line = output
+            .lines()
+            .next()
+            .ok_or_else(|| eyre::eyre!("No version string found"))?;
+
+        let re = regex::Regex::new(r"\d+\.\d+\.\d+")?;
+        let version = re
+            .find(line)
+            .ok_or_else(|| eyre::eyre!("Failed to extract version"))?
+            .as_str()
+            .to_string();
+
+        let variant = if line.contains("Lix") {
+            NixVariant::Lix
+        } else {
+            NixVariant::CNix
+        };
+
+        Ok(Self { variant, version })
+    }
+
+    /// Whether `nix flake update` takes the flake with `--flake`, instead of as an argument
+    ///
+    /// Nix changed this in 2.19, while Lix kept the interface of 2.18 it forked from.
+    pub fn flake_update_needs_flag(&self) -> bool {
+        match self.variant {
+            NixVariant::CNix => compare_semver(&self.version, "2.19.0")
+                .is_ok_and(|ordering| ordering != std::cmp::Ordering::Less),
+            NixVariant::Lix => false,
+        }
+    }
+}
+
+/// Probes the version of the nix binary in use, unless the variant was set explicitly
+pub fn nix_version() -> Result<NixVersion> {
+    let output = Command::new(nix_bin()).arg("--version").output()?;
+    let mut version = NixVersion::parse(str::from_utf8(&output.stdout)?)?;
+
+    if let Some(variant) = NIX_VARIANT.get() {
+        version.variant = *variant;
+    }
+
+    Ok(version)
+}
+
+/// Returns the nix system double of this machine, like `x86_64-linux`.
+pub fn current_system() -> String {
+    format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// Checks whether this machine is able to build derivations for `system`.
 ///
-/// # Returns
+/// Besides the native system, this is the case when a binfmt interpreter is registered for
+/// `system` (as done by `boot.binfmt.emulatedSystems`), or when a remote builder in
+/// `/etc/nix/machines` advertises it.
+pub fn can_build_for(system: &str) -> bool {
+    if system == current_system() {
+        return true;
+    }
+
+    if Path::new("/proc/sys/fs/binfmt_misc").join(system).exists() {
+        return true;
+    }
+
+    std::fs::read_to_string("/etc/nix/machines")
+        .map(|machines| {
+            machines
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .any(|line| {
+                    line.split_whitespace()
+                        .any(|field| field.split(',').any(|s| s == system))
+                })
+        })
+        .unwrap_or(false)
+}
+
+/// Checks whether an executable called `program` can be found in `$PATH`.
+pub fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Store paths and their info from `nix path-info --json`
 ///
-/// * `Result<String>` - The Nix version string or an error if the version cannot be retrieved.
-pub fn get_nix_version() -> Result<String> {
-    let output = Command::new("nix").arg("--version").output()?;
-
-    let output_str = str::from_utf8(&output.stdout)?;
-    let version_str = output_str
-        .lines()
-        .next()
-        .ok_or_else(|| eyre::eyre!("No version string found"))?;
-
-    // Extract the version substring using a regular expression
-    let re = regex::Regex::new(r"\d+\.\d+\.\d+")?;
-    if let Some(captures) = re.captures(version_str) {
-        let version = captures
-            .get(0)
-            .ok_or_else(|| eyre::eyre!("No version match found"))?
-            .as_str();
-        return Ok(version.to_string());
-    }
-
-    Err(eyre::eyre!("Failed to extract version"))
+/// Nix 2.19 changed the output from a list of objects with a `path` to an object keyed by path,
+/// with `null` for the paths that aren't valid.
+pub fn path_info_entries(json: &str) -> Result<Vec<(PathBuf, serde_json::Value)>> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).wrap_err("Parsing nix path-info output")?;
+
+    Ok(match value {
+        serde_json::Value::Object(paths) => paths
+            .into_iter()
+            .map(|(path, info)| (PathBuf::from(path), info))
+            .collect(),
+        serde_json::Value::Array(infos) => infos
+            .into_iter()
+            .filter_map(|info| {
+                let path = PathBuf::from(info.get("path")?.as_str()?);
+                Some((path, info))
+            })
+            .collect(),
+        other => bail!("Unexpected nix path-info output: {other}"),
+    })
+}
+
+/// Formats a number of bytes with binary units, like `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Formats the difference between two sizes, with its sign
+pub fn format_size_delta(from: u64, to: u64) -> String {
+    if to >= from {
+        format!("+{}", format_size(to - from))
+    } else {
+        format!("-{}", format_size(from - to))
+    }
+}
+
+/// Extra nix flags, split like a shell would, e.g. from `NH_NIX_FLAGS`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixFlags(pub Vec<String>);
+
+impl FromStr for NixFlags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        shlex::split(s)
+            .map(NixFlags)
+            .ok_or_else(|| format!("couldn't split {s:?} into arguments, check its quoting"))
+    }
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+
+    assert_eq!(format_size_delta(1024, 3 * 1024), "+2.0 KiB");
+    assert_eq!(format_size_delta(5 * 1024 * 1024, 1024 * 1024), "-4.0 MiB");
+    assert_eq!(format_size_delta(100, 100), "+0 B");
+}
+
+#[test]
+fn test_nix_flags() {
+    assert_eq!(
+        "--option narinfo-cache-negative-ttl 0".parse(),
+        Ok(NixFlags(vec![
+            String::from("--option"),
+            String::from("narinfo-cache-negative-ttl"),
+            String::from("0"),
+        ]))
+    );
+    assert_eq!(
+        r#"--option extra-substituters 'https://a.example https://b.example' --impure"#.parse(),
+        Ok(NixFlags(vec![
+            String::from("--option"),
+            String::from("extra-substituters"),
+            String::from("https://a.example https://b.example"),
+            String::from("--impure"),
+        ]))
+    );
+    assert_eq!("".parse(), Ok(NixFlags::default()));
+    assert!(r#"--option "unterminated"#.parse::<NixFlags>().is_err());
+}
+
+#[test]
+fn test_nix_variant() {
+    let lix = NixVersion::parse("nix (Lix, like Nix) 2.91.1\nSystem type: x86_64-linux\n").unwrap();
+    assert_eq!(
+        lix,
+        NixVersion {
+            variant: NixVariant::Lix,
+            version: String::from("2.91.1"),
+        }
+    );
+    assert!(!lix.flake_update_needs_flag());
+
+    let cnix = NixVersion::parse("nix (Nix) 2.24.9\n").unwrap();
+    assert_eq!(cnix.variant, NixVariant::CNix);
+    assert!(cnix.flake_update_needs_flag());
+
+    let first = NixVersion::parse("nix (Nix) 2.19.0\n").unwrap();
+    assert!(first.flake_update_needs_flag());
+
+    let old = NixVersion::parse("nix (Nix) 2.18.1\n").unwrap();
+    assert!(!old.flake_update_needs_flag());
+
+    assert!(NixVersion::parse("").is_err());
 }
@@ -0,0 +1,88 @@
+//! Versions of nh and of the tools it runs, for bug reports
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::interface::{NHRunnable, VersionArgs};
+use crate::util::{self, NixVariant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct VersionInfo {
+    nh: String,
+    /// `None` if `nix --version` couldn't be run or parsed
+    nix: Option<String>,
+    nix_variant: Option<NixVariant>,
+    nom: bool,
+    nvd: bool,
+}
+
+impl VersionInfo {
+    fn probe() -> Self {
+        let nix = util::nix_version().ok();
+        Self {
+            nh: crate::NH_VERSION.to_string(),
+            nix_variant: nix.as_ref().map(|nix| nix.variant),
+            nix: nix.map(|nix| nix.version),
+            nom: util::in_path("nom"),
+            nvd: util::in_path("nvd"),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let found = |found| if found { "found" } else { "not found" };
+        let nix = match (&self.nix, self.nix_variant) {
+            (Some(version), Some(NixVariant::Lix)) => format!("{version} (Lix)"),
+            (Some(version), _) => version.clone(),
+            (None, _) => String::from("not found"),
+        };
+
+        format!(
+            "nh   {}\nnix  {nix}\nnom  {}\nnvd  {}",
+            self.nh,
+            found(self.nom),
+            found(self.nvd)
+        )
+    }
+}
+
+impl NHRunnable for VersionArgs {
+    fn run(&self) -> Result<()> {
+        let info = VersionInfo::probe();
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        } else {
+            println!("{}", info.to_text());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_version_json() {
+    let info = VersionInfo {
+        nh: crate::NH_VERSION.to_string(),
+        nix: Some(String::from("2.91.1")),
+        nix_variant: Some(NixVariant::Lix),
+        nom: true,
+        nvd: false,
+    };
+
+    let json = serde_json::to_value(&info).unwrap();
+    let keys: Vec<&str> = json
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(keys, ["nh", "nix", "nix_variant", "nom", "nvd"]);
+    assert_eq!(json["nh"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(json["nix_variant"], "lix");
+
+    assert_eq!(
+        info.to_text(),
+        format!(
+            "nh   {}\nnix  2.91.1 (Lix)\nnom  found\nnvd  not found",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+}
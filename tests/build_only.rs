@@ -0,0 +1,36 @@
+//! `nh os build-only` as a script sees it, run against a stand-in for nix
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_build_only_stdout() {
+    let tmp = tempfile::tempdir().unwrap();
+    // Nothing gets linked with --no-link, so the path doesn't need to exist
+    let system = Path::new("/nix/store/00000000000000000000000000000000-nixos-system-host");
+    let nix = common::fake_nix(tmp.path(), system);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nh"))
+        .arg("--nix-bin")
+        .arg(&nix)
+        .args(["os", "build-only", "--no-preflight", "--nom", "never"])
+        // Root CI containers run the test too
+        .arg("--bypass-root-check")
+        .args(["-H", "host"])
+        .arg(tmp.path())
+        .env("HOME", tmp.path())
+        .env("XDG_CONFIG_HOME", tmp.path())
+        .env_remove("FLAKE")
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n", system.display())
+    );
+    assert!(stderr.contains("evaluating derivation"), "{stderr}");
+}
@@ -0,0 +1,26 @@
+//! Helpers shared by the integration tests
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Writes an executable `nix` to `dir` that only knows `nix build`, and returns its path
+///
+/// The build reports `system` as its output, with some noise on stderr like a real build.
+pub fn fake_nix(dir: &Path, system: &Path) -> PathBuf {
+    let script = format!(
+        r#"#!/bin/sh
+[ "$1" = build ] || exit 1
+echo "evaluating derivation" >&2
+while [ $# -gt 0 ]; do
+    [ "$1" = --out-link ] && ln -s '{system}' "$2"
+    shift
+done
+echo '{system}'
+"#,
+        system = system.display()
+    );
+    let nix = dir.join("nix");
+    std::fs::write(&nix, script).unwrap();
+    std::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o755)).unwrap();
+    nix
+}
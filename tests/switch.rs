@@ -0,0 +1,31 @@
+//! The library entrypoint, run against a stand-in for nix
+
+mod common;
+
+use nh::{SwitchMode, SwitchOptions};
+
+#[test]
+fn test_dry_switch() {
+    let tmp = tempfile::tempdir().unwrap();
+    let system = tmp.path().join("nixos-system-host");
+    std::fs::create_dir_all(&system).unwrap();
+    let nix = common::fake_nix(tmp.path(), &system);
+
+    let outcome = nh::switch(SwitchOptions {
+        flake: tmp.path().display().to_string(),
+        hostname: Some(String::from("host")),
+        mode: SwitchMode::Switch,
+        dry: true,
+        no_preflight: true,
+        no_diff: true,
+        nix_bin: Some(nix.display().to_string()),
+        // Root CI containers run the test too
+        allow_root: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(outcome.built_path, Some(system));
+    assert!(!outcome.activated);
+    assert_eq!(outcome.new_generation, None);
+}